@@ -0,0 +1,303 @@
+//! Encodes a synthetic demo with ten players talking over each other throughout, once with a
+//! single job and once with as many jobs as there are players (or cores), and prints how much
+//! faster the second run was.
+//!
+//! Run with `cargo bench --bench jobs`. The demo is built in memory, so there's nothing to
+//! download; `BENCH_SECONDS` sets how long it is (60 by default).
+
+use dem::types::{
+    ByteString, Demo, DemoInfo, Directory, DirectoryEntry, EngineMessage, Frame, FrameData, Header,
+    MessageData, MoveVars, NetMessage, NetworkMessage, NetworkMessageType, RefParams, SequenceInfo,
+    SvcVoiceData, UserCmd,
+};
+use goldsrc_voice_extract::{ExtractConfig, Output, PlayerFilter, extract};
+use opus::{Application, Channels, Encoder};
+use rsmpeg::avcodec::AVCodec;
+use rsmpeg::avformat::AVFormatContextOutput;
+use rsmpeg::ffi;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+const SPEAKERS: u64 = 10;
+const SAMPLE_RATE: u16 = 24_000;
+/// Samples in every opus frame, 20ms
+const FRAME_SIZE: usize = 480;
+/// Demo frames per second, two per opus frame
+const DEMO_FPS: usize = 100;
+/// Steam id of the first speaker, the others count up from it
+const FIRST_STEAM_ID: u64 = 76561197960265728;
+const RUNS: usize = 3;
+
+/// CRC-32 steam voice data is checked with
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Steam voice data carrying a single opus frame
+fn voice_data(steam_id: u64, seq: u16, opus_frame: &[u8]) -> Vec<u8> {
+    let mut data = steam_id.to_le_bytes().to_vec();
+    data.push(11); // sample rate
+    data.extend(SAMPLE_RATE.to_le_bytes());
+    data.push(6); // opus PLC
+    data.extend((opus_frame.len() as u16 + 4).to_le_bytes());
+    data.extend((opus_frame.len() as u16).to_le_bytes());
+    data.extend(seq.to_le_bytes());
+    data.extend(opus_frame);
+    let crc = crc32(&data);
+    data.extend(crc.to_le_bytes());
+    data
+}
+
+fn network_message(messages: Vec<NetMessage>) -> FrameData {
+    let info = DemoInfo {
+        timestamp: 0.0,
+        refparams: RefParams {
+            view_origin: vec![0.0; 3],
+            view_angles: vec![0.0; 3],
+            forward: vec![0.0; 3],
+            right: vec![0.0; 3],
+            up: vec![0.0; 3],
+            frame_time: 0.0,
+            time: 0.0,
+            intermission: 0,
+            paused: 0,
+            spectator: 0,
+            on_ground: 0,
+            water_level: 0,
+            sim_vel: vec![0.0; 3],
+            sim_org: vec![0.0; 3],
+            view_height: vec![0.0; 3],
+            ideal_pitch: 0.0,
+            cl_viewangles: vec![0.0; 3],
+            health: 0,
+            crosshair_angle: vec![0.0; 3],
+            view_size: 0.0,
+            punch_angle: vec![0.0; 3],
+            max_clients: SPEAKERS as i32,
+            view_entity: 0,
+            player_num: 0,
+            max_entities: 0,
+            demo_playback: 0,
+            hardware: 0,
+            smoothing: 0,
+            ptr_cmd: 0,
+            ptr_move_vars: 0,
+            view_port: vec![0; 4],
+            next_view: 0,
+            only_client_draw: 0,
+        },
+        usercmd: UserCmd {
+            lerp_msec: 0,
+            msec: 0,
+            unknown1: 0,
+            view_angles: vec![0.0; 3],
+            forward_move: 0.0,
+            side_move: 0.0,
+            up_move: 0.0,
+            light_level: 0,
+            unknonwn2: 0,
+            buttons: 0,
+            impulse: 0,
+            weapon_select: 0,
+            unknown3: 0,
+            unknown4: 0,
+            impact_index: 0,
+            impact_position: vec![0.0; 3],
+        },
+        movevars: MoveVars {
+            gravity: 0.0,
+            stopspeed: 0.0,
+            maxspeed: 0.0,
+            spectatormaxspeed: 0.0,
+            accelerate: 0.0,
+            airaccelerate: 0.0,
+            wateraccelerate: 0.0,
+            friction: 0.0,
+            edgefriction: 0.0,
+            waterfriction: 0.0,
+            entgravity: 0.0,
+            bounce: 0.0,
+            stepsize: 0.0,
+            maxvelocity: 0.0,
+            zmax: 0.0,
+            wave_height: 0.0,
+            footsteps: 0,
+            sky_name: ByteString(vec![0; 32]),
+            rollangle: 0.0,
+            rollspeed: 0.0,
+            skycolor: vec![0.0; 3],
+            skyvec: vec![0.0; 3],
+        },
+        view: vec![0.0; 3],
+        viewmodel: 0,
+    };
+    let sequence_info = SequenceInfo {
+        incoming_sequence: 0,
+        incoming_acknowledged: 0,
+        incoming_reliable_acknowledged: 0,
+        incoming_reliable_sequence: 0,
+        outgoing_sequence: 0,
+        reliable_sequence: 0,
+        last_reliable_sequence: 0,
+    };
+    FrameData::NetworkMessage(Box::new((
+        NetworkMessageType::Normal,
+        NetworkMessage {
+            info,
+            sequence_info,
+            message_length: 0,
+            messages: MessageData::Parsed(messages),
+        },
+    )))
+}
+
+/// A demo `seconds` long in which every speaker talks all the way through, each in a tone of
+/// their own with some noise on top so the encoders have something to work with
+fn demo(seconds: usize) -> Demo {
+    let mut encoders: Vec<Encoder> = (0..SPEAKERS)
+        .map(|_| Encoder::new(SAMPLE_RATE.into(), Channels::Mono, Application::Voip).unwrap())
+        .collect();
+    let mut noise = 0x2545_f491u32;
+    let mut pcm = vec![0i16; FRAME_SIZE];
+    let mut opus_frame = vec![0u8; 4000];
+    let mut frames = Vec::new();
+    for index in 0..seconds * DEMO_FPS {
+        let mut messages = Vec::new();
+        if index % 2 == 0 {
+            let seq = (index / 2) as u16;
+            for (speaker, encoder) in encoders.iter_mut().enumerate() {
+                let pitch = 150.0 + 40.0 * speaker as f32;
+                for (i, sample) in pcm.iter_mut().enumerate() {
+                    let t = (index / 2 * FRAME_SIZE + i) as f32 / SAMPLE_RATE as f32;
+                    noise ^= noise << 13;
+                    noise ^= noise >> 17;
+                    noise ^= noise << 5;
+                    let tone = (t * pitch * std::f32::consts::TAU).sin() * 8000.0;
+                    *sample = tone as i16 + (noise >> 20) as i16 - 2048;
+                }
+                let len = encoder.encode(&pcm, &mut opus_frame).unwrap();
+                let data = voice_data(FIRST_STEAM_ID + speaker as u64, seq, &opus_frame[..len]);
+                messages.push(NetMessage::EngineMessage(Box::new(
+                    EngineMessage::SvcVoiceData(SvcVoiceData {
+                        player_index: speaker as u8,
+                        size: data.len() as u16,
+                        data,
+                    }),
+                )));
+            }
+        }
+        frames.push(Frame {
+            time: index as f32 / DEMO_FPS as f32,
+            frame: index as i32,
+            frame_data: network_message(messages),
+        });
+    }
+    Demo {
+        header: Header {
+            magic: b"HLDEMO\0\0".to_vec(),
+            demo_protocol: 5,
+            network_protocol: 48,
+            map_name: ByteString(vec![0; 260]),
+            game_directory: ByteString(vec![0; 260]),
+            map_checksum: 0,
+            directory_offset: 0,
+        },
+        directory: Directory {
+            entries: vec![DirectoryEntry {
+                type_: 1,
+                description: ByteString(vec![0; 64]),
+                flags: 0,
+                cd_track: 0,
+                track_time: seconds as f32,
+                frame_count: frames.len() as i32,
+                frame_offset: 0,
+                file_length: 0,
+                frames,
+            }],
+        },
+        _aux: None,
+    }
+}
+
+fn config(jobs: NonZeroUsize) -> ExtractConfig {
+    ExtractConfig {
+        codec: Some(
+            AVCodec::find_encoder(ffi::AV_CODEC_ID_AAC).expect("ffmpeg has an AAC encoder"),
+        ),
+        bitrate: None,
+        codec_options: Vec::new(),
+        resample_options: Vec::new(),
+        encoder_rate: None,
+        encoder_format: None,
+        chapters: false,
+        players: PlayerFilter::All,
+        mix: false,
+        pan: None,
+        mix_weights: HashMap::new(),
+        normalize: None,
+        gain: 0.0,
+        player_gains: HashMap::new(),
+        offset: 0.0,
+        player_offsets: HashMap::new(),
+        trim_silence: None,
+        start: None,
+        end: None,
+        jobs,
+        progress: false,
+        jitter_buffer: goldsrc_voice_extract::DEFAULT_JITTER_BUFFER_SECONDS,
+        max_plc_frames: goldsrc_voice_extract::decoder::DEFAULT_MAX_PLC_FRAMES,
+        fade_in: 0.0,
+        fade_out: 0.0,
+        highpass: None,
+        dual_mono: false,
+    }
+}
+
+/// Fastest of a few extractions of `demo` into a container that throws everything away
+fn time_extraction(demo: &Demo, jobs: NonZeroUsize) -> Duration {
+    let config = config(jobs);
+    (0..RUNS)
+        .map(|_| {
+            let mut fmt_ctx = AVFormatContextOutput::builder()
+                .format_name(c"null")
+                .build()
+                .unwrap();
+            let start = Instant::now();
+            extract(demo, &config, Output::Muxed(&mut fmt_ctx)).unwrap();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let seconds = std::env::var("BENCH_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(60);
+    let demo = demo(seconds);
+    let cores = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let jobs = NonZeroUsize::new(cores.min(SPEAKERS as usize)).unwrap();
+
+    let single = time_extraction(&demo, NonZeroUsize::MIN);
+    let parallel = time_extraction(&demo, jobs);
+    println!("{SPEAKERS} speakers, {seconds}s of voice each, encoded as AAC");
+    println!("  --jobs 1: {:>8.3}s", single.as_secs_f64());
+    println!("  --jobs {jobs}: {:>8.3}s", parallel.as_secs_f64());
+    println!(
+        "  speedup: {:.2}x",
+        single.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
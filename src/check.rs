@@ -0,0 +1,101 @@
+use dem::types::Demo;
+use std::collections::HashMap;
+
+use crate::decoder::{DecoderStats, SteamVoiceDecoder, decoding_rate};
+use crate::discover::{parse_voice_data, playback_frames, voice_messages};
+use crate::{ExtractConfig, SAMPLE_FMT_S16, check_voice_data, resolve_names, voice_format};
+
+/// How decoding the voice data of a demo went, see [`check_voice`]
+#[derive(Debug, Clone, Default)]
+pub struct VoiceCheck {
+    /// `svc_voicedata` messages that weren't steam voice data
+    pub unparsed_messages: usize,
+    /// Every checked player, by steam id
+    pub players: HashMap<u64, PlayerCheck>,
+}
+
+impl VoiceCheck {
+    /// Whether every voice message of every player was read and decoded
+    pub fn is_clean(&self) -> bool {
+        self.unparsed_messages == 0 && self.players.values().all(|player| player.errors == 0)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PlayerCheck {
+    /// See [`crate::PlayerSummary::name`]
+    pub name: String,
+    /// Voice messages that failed to decode, each of them already logged
+    pub errors: usize,
+    /// See [`crate::PlayerSummary::decoded_samples`]
+    pub decoded_samples: usize,
+    pub decoder: DecoderStats,
+}
+
+/// Decodes the voice data of every player [`ExtractConfig::players`] allows in the time range of
+/// `config`, without playing it back or writing anything, and reports what went wrong.
+///
+/// Much cheaper than [`crate::extract`] into [`crate::Output::Discard`], but it only catches
+/// problems with the voice data itself.
+pub fn check_voice(
+    demo: &Demo,
+    config: &ExtractConfig,
+) -> Result<VoiceCheck, Box<dyn std::error::Error>> {
+    check_voice_data(demo)?;
+    let sample_rate = decoding_rate(voice_format(demo).sample_rate);
+    let names = resolve_names(demo);
+
+    let mut check = VoiceCheck::default();
+    let mut decoders = HashMap::new();
+    let mut tmp = Vec::new();
+    let frames = playback_frames(demo).filter(|frame| !config.after_end(frame.time));
+    for frame in frames {
+        for svc_voice_data in voice_messages(frame) {
+            let Ok(steam_voice_data) = parse_voice_data(&svc_voice_data.data) else {
+                check.unparsed_messages += 1;
+                continue;
+            };
+            let steam_id = steam_voice_data.steam_id;
+            if !config.players.allows(steam_id) {
+                continue;
+            }
+            let decoder = match decoders.entry(steam_id) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => entry.insert(
+                    SteamVoiceDecoder::new(SAMPLE_FMT_S16, sample_rate)?
+                        .with_max_plc_frames(config.max_plc_frames),
+                ),
+            };
+            let player = check
+                .players
+                .entry(steam_id)
+                .or_insert_with(|| PlayerCheck {
+                    name: names
+                        .get(&steam_id)
+                        .cloned()
+                        .unwrap_or_else(|| steam_id.to_string()),
+                    ..PlayerCheck::default()
+                });
+            tmp.resize(decoder.output_len(&steam_voice_data), 0);
+            match decoder.decode(steam_voice_data, &mut tmp) {
+                // Voice before the start is only decoded to keep up with the sequence numbers
+                Ok(_) if config.before_start(frame.time) => {}
+                Ok(samples_written) => player.decoded_samples += samples_written / 2,
+                Err(e) => {
+                    log::warn!("{} at {:.3}s: {e}", player.name, frame.time);
+                    player.errors += 1;
+                }
+            }
+        }
+    }
+    if check.unparsed_messages > 0 {
+        log::warn!(
+            "{} svc_voicedata messages weren't steam voice data",
+            check.unparsed_messages
+        );
+    }
+    for (steam_id, decoder) in decoders {
+        check.players.get_mut(&steam_id).unwrap().decoder = decoder.stats();
+    }
+    Ok(check)
+}
@@ -0,0 +1,34 @@
+use std::io::{self, Write};
+
+use crate::ExtractSummary;
+
+/// Writes every interval a player was talking in as a
+/// `steam_id,start_seconds,end_seconds,duration_seconds` row, ordered by start
+pub fn write_speech_csv(mut writer: impl Write, summary: &ExtractSummary) -> io::Result<()> {
+    let mut rows: Vec<_> = summary
+        .players
+        .iter()
+        .flat_map(|(&steam_id, player)| {
+            player
+                .speech
+                .iter()
+                .map(move |interval| (steam_id, interval))
+        })
+        .collect();
+    rows.sort_by(|(a_id, a), (b_id, b)| a.start.total_cmp(&b.start).then(a_id.cmp(b_id)));
+
+    writeln!(
+        writer,
+        "steam_id,start_seconds,end_seconds,duration_seconds"
+    )?;
+    for (steam_id, interval) in rows {
+        writeln!(
+            writer,
+            "{steam_id},{:.3},{:.3},{:.3}",
+            interval.start,
+            interval.end,
+            interval.end - interval.start
+        )?;
+    }
+    writer.flush()
+}
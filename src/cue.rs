@@ -0,0 +1,73 @@
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::ExtractSummary;
+
+/// CUE sheets can't hold more tracks than this
+const MAX_TRACKS: usize = 99;
+/// CUE sheets count time in CD frames
+const FRAMES_PER_SECOND: u64 = 75;
+
+/// Writes a CUE sheet for `audio_file` with a track every time another player starts talking,
+/// titled after them. Players talking over each other only start a track when the one that was
+/// already talking stops in between, and tracks past the 99th CUE sheets allow are left out.
+pub fn write_cue(
+    mut writer: impl Write,
+    summary: &ExtractSummary,
+    audio_file: &str,
+) -> io::Result<()> {
+    let mut bursts: Vec<_> = summary
+        .players
+        .iter()
+        .flat_map(|(&steam_id, player)| {
+            player
+                .speech
+                .iter()
+                .map(move |interval| (interval.start, steam_id, player.name.as_str()))
+        })
+        .collect();
+    bursts.sort_by(|(a, a_id, _), (b, b_id, _)| a.total_cmp(b).then(a_id.cmp(b_id)));
+
+    // Track indices have to keep increasing, so a track starts on a new frame with a new player
+    let mut tracks: Vec<(u64, u64, &str)> = Vec::new();
+    for (start, steam_id, name) in bursts {
+        let frame = (start.max(0.0) * FRAMES_PER_SECOND as f64).round() as u64;
+        match tracks.last() {
+            Some(&(last_frame, last_id, _)) if last_id == steam_id || last_frame >= frame => {}
+            _ => tracks.push((frame, steam_id, name)),
+        }
+    }
+    if tracks.len() > MAX_TRACKS {
+        log::warn!(
+            "The CUE sheet only has room for {MAX_TRACKS} of {} tracks, leaving out the rest",
+            tracks.len()
+        );
+        tracks.truncate(MAX_TRACKS);
+    }
+
+    let file_type = match Path::new(audio_file).extension() {
+        Some(extension) if extension.eq_ignore_ascii_case("mp3") => "MP3",
+        Some(extension) if extension.eq_ignore_ascii_case("aiff") => "AIFF",
+        // Players take WAVE for anything they can decode themselves
+        _ => "WAVE",
+    };
+    writeln!(writer, "FILE {} {file_type}", quote(audio_file))?;
+    for (index, (frame, steam_id, name)) in tracks.into_iter().enumerate() {
+        writeln!(writer, "  TRACK {:02} AUDIO", index + 1)?;
+        writeln!(writer, "    TITLE {}", quote(name))?;
+        writeln!(writer, "    REM STEAM_ID {steam_id}")?;
+        writeln!(
+            writer,
+            "    INDEX 01 {:02}:{:02}:{:02}",
+            frame / FRAMES_PER_SECOND / 60,
+            frame / FRAMES_PER_SECOND % 60,
+            frame % FRAMES_PER_SECOND
+        )?;
+    }
+    writer.flush()
+}
+
+/// Quotes a CUE sheet string, which has no way to escape a quote
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "'"))
+}
@@ -1,24 +1,96 @@
 use opus::{Channels, Decoder};
-use rsmpeg::ffi::{AV_SAMPLE_FMT_FLT, AV_SAMPLE_FMT_S16};
-use steam_audio_codec::{Packet, SteamVoiceData};
+use steam_audio_codec::{Packet, SteamAudioError, SteamVoiceData};
 use thiserror::Error;
 
-use crate::SAMPLE_RATE;
+use crate::{SAMPLE_FMT_FLT, SAMPLE_FMT_S16};
 
 const FRAME_SIZE: usize = 960;
+/// Longest an opus packet can last, in milliseconds
+const MAX_PACKET_DURATION_MS: usize = 120;
+/// Default for [`SteamVoiceDecoder::with_max_plc_frames`]
+pub const DEFAULT_MAX_PLC_FRAMES: usize = 10;
+/// Most frames [`SteamVoiceDecoder::with_max_plc_frames`] conceals in a row. Concealment can't
+/// plausibly cover a longer gap, and every message needs room for them
+pub const MAX_PLC_FRAMES: usize = 50;
+/// Sample rates opus can decode to
+const OPUS_SAMPLE_RATES: [i32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+
+/// Picks the rate to decode voice data announced at `sample_rate` to: the rate itself if opus
+/// supports it, otherwise the next higher one opus does
+pub fn decoding_rate(sample_rate: i32) -> i32 {
+    OPUS_SAMPLE_RATES
+        .into_iter()
+        .find(|&rate| rate >= sample_rate)
+        .unwrap_or(48_000)
+}
 
 #[derive(Debug, Error)]
 pub enum DecoderError {
     #[error("Insufficient data")]
     InsufficientData,
+    #[error("Insufficient space in output buffer")]
+    InsufficientOutputBuffer,
+    #[error("Invalid voice packet: {0}")]
+    InvalidPacket(SteamAudioError),
     #[error("Opus Error: {0}")]
-    OpusError(#[from] opus::Error),
+    Opus(#[from] opus::Error),
+}
+
+/// Counts of what a [`SteamVoiceDecoder`] has decoded so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecoderStats {
+    /// Opus frames decoded from voice data
+    pub packets: usize,
+    /// Opus frames missing from the sequence, whether or not they were concealed
+    pub lost_packets: usize,
+    /// Frames filled in by packet loss concealment
+    pub concealed_frames: usize,
+    /// Times the decoder started over, because the sender reset their encoder or changed its
+    /// sample rate, or because sequence numbers went backwards
+    pub resets: usize,
 }
 
 pub struct SteamVoiceDecoder {
     decoder: Decoder,
-    seq: u16,
+    /// Rate the decoder was created with, what every decoded sample is at
+    sample_rate: i32,
+    /// Rate the voice data last announced, `None` until it does
+    voice_rate: Option<u16>,
+    /// Sequence number the next opus frame should have, `None` right after [`Self::reset`]
+    seq: Option<u16>,
     decoder_kind: SampleDecoderKind,
+    max_plc_frames: usize,
+    stats: DecoderStats,
+}
+
+/// A single entry of the opus data in a voice packet
+pub(crate) enum OpusFrame<'a> {
+    /// The sender's encoder was reset, sequence numbers start over from 0
+    Reset,
+    /// A frame of opus data. Some clients send frames without any data as keepalives, which
+    /// take up a sequence number but don't carry any audio
+    Frame { seq: u16, data: &'a [u8] },
+}
+
+/// Splits the opus data of a voice packet into its frames, stripping the length and sequence
+/// number framing
+pub(crate) fn opus_frames(mut data: &[u8]) -> Result<Vec<OpusFrame<'_>>, DecoderError> {
+    let mut frames = Vec::new();
+    while data.len() > 2 {
+        let (len, remainder) = read_u16(data)?;
+        data = remainder;
+        if len == u16::MAX {
+            frames.push(OpusFrame::Reset);
+            continue;
+        }
+        let (seq, remainder) = read_u16(data)?;
+        let Some((frame, remainder)) = remainder.split_at_checked(len as usize) else {
+            return Err(DecoderError::InsufficientData);
+        };
+        data = remainder;
+        frames.push(OpusFrame::Frame { seq, data: frame });
+    }
+    Ok(frames)
 }
 
 fn read_bytes<const N: usize>(data: &[u8]) -> Result<([u8; N], &[u8]), DecoderError> {
@@ -40,80 +112,201 @@ enum SampleDecoderKind {
 }
 
 impl SampleDecoderKind {
+    fn bytes_per_sample(&self) -> usize {
+        match self {
+            SampleDecoderKind::S16 => std::mem::size_of::<i16>(),
+            SampleDecoderKind::Float => std::mem::size_of::<f32>(),
+        }
+    }
+
+    /// Decodes a single opus frame into `output`, or conceals a lost one when `input` is empty.
+    /// Fails with [`DecoderError::InsufficientOutputBuffer`] instead of writing past `output`.
     pub fn decode(
         &self,
         decoder: &mut Decoder,
         input: &[u8],
         output: &mut [u8],
     ) -> Result<usize, DecoderError> {
-        match self {
+        let available = output.len() / self.bytes_per_sample();
+        let output_length = if input.is_empty() {
+            FRAME_SIZE
+        } else {
+            available
+        };
+        if output_length > available || output_length == 0 {
+            return Err(DecoderError::InsufficientOutputBuffer);
+        }
+        let n = match self {
             SampleDecoderKind::S16 => {
-                let output_length = if input.is_empty() {
-                    FRAME_SIZE
-                } else {
-                    output.len()
-                } / std::mem::size_of::<i16>();
                 let out = unsafe {
                     std::slice::from_raw_parts_mut(output.as_mut_ptr() as *mut i16, output_length)
                 };
-                let n = decoder.decode(input, out, false)?;
-                Ok(n * std::mem::size_of::<i16>())
+                decoder.decode(input, out, false)
             }
             SampleDecoderKind::Float => {
-                let output_length = if input.is_empty() {
-                    FRAME_SIZE
-                } else {
-                    output.len()
-                } / std::mem::size_of::<f32>();
                 let out = unsafe {
                     std::slice::from_raw_parts_mut(output.as_mut_ptr() as *mut f32, output_length)
                 };
-                let n = decoder.decode_float(input, out, false)?;
-                Ok(n * std::mem::size_of::<f32>())
+                decoder.decode_float(input, out, false)
             }
         }
+        .map_err(|err| match err.code() {
+            opus::ErrorCode::BufferTooSmall => DecoderError::InsufficientOutputBuffer,
+            _ => DecoderError::Opus(err),
+        })?;
+        Ok(n * self.bytes_per_sample())
     }
 }
 
 impl SteamVoiceDecoder {
-    pub fn new(sample_format: i32) -> Result<Self, DecoderError> {
-        let decoder = Decoder::new(SAMPLE_RATE as u32, Channels::Mono)?;
+    /// Creates a decoder producing `sample_format` samples at `sample_rate`, which has to be one
+    /// opus supports (see [`decoding_rate`]).
+    ///
+    /// Opus resamples internally, so voice data announcing a different rate, even one that
+    /// changes mid-stream, is still decoded to `sample_rate` and plays back at the right speed.
+    pub fn new(sample_format: i32, sample_rate: i32) -> Result<Self, DecoderError> {
+        let decoder = Decoder::new(sample_rate as u32, Channels::Mono)?;
         let decoder_kind = match sample_format {
-            AV_SAMPLE_FMT_S16 => SampleDecoderKind::S16,
-            AV_SAMPLE_FMT_FLT => SampleDecoderKind::Float,
+            SAMPLE_FMT_S16 => SampleDecoderKind::S16,
+            SAMPLE_FMT_FLT => SampleDecoderKind::Float,
             _ => panic!("decoder created with sample format that we didn't account for!"),
         };
 
         Ok(Self {
             decoder,
-            seq: 0,
+            sample_rate,
+            voice_rate: None,
+            seq: Some(0),
             decoder_kind,
+            max_plc_frames: DEFAULT_MAX_PLC_FRAMES,
+            stats: DecoderStats::default(),
         })
     }
 
+    /// Forgets all decoder state, for when playback starts in the middle of the voice data. The
+    /// next frame is decoded without concealing the frames that came before it
+    pub fn reset(&mut self) -> Result<(), DecoderError> {
+        self.decoder.reset_state()?;
+        self.seq = None;
+        Ok(())
+    }
+
+    /// Limits how many lost frames are concealed when the sequence numbers skip ahead.
+    ///
+    /// Only the first `max_plc_frames` frames of a longer gap are filled in, the rest of it is
+    /// dropped. During playback that shows up as silence once the player's buffer runs dry, so
+    /// speech after the gap still starts at the right time. Limited to [`MAX_PLC_FRAMES`].
+    pub fn with_max_plc_frames(mut self, max_plc_frames: usize) -> Self {
+        self.max_plc_frames = max_plc_frames.min(MAX_PLC_FRAMES);
+        self
+    }
+
+    pub fn stats(&self) -> DecoderStats {
+        self.stats
+    }
+
+    /// Size in bytes of an output buffer [`Self::decode`] can decode `voice_data` into, following
+    /// the sequence numbers the same way to leave room for the frames it will conceal
+    pub fn output_len(&self, voice_data: &SteamVoiceData) -> usize {
+        let mut voice_rate = self.voice_rate;
+        let mut expected = self.seq;
+        let mut samples = 0;
+        // Invalid voice data fails to decode before using any more of the buffer
+        for packet in voice_data.packets().map_while(Result::ok) {
+            match packet {
+                Packet::SampleRate(rate) => {
+                    if voice_rate.is_some_and(|voice_rate| voice_rate != rate) {
+                        expected = None;
+                    }
+                    voice_rate = Some(rate);
+                }
+                Packet::Silence(silence) => samples += self.silence_samples(silence, voice_rate),
+                Packet::OpusPlc(opus) => {
+                    for frame in opus_frames(opus.as_slice()).unwrap_or_default() {
+                        let (seq, data) = match frame {
+                            OpusFrame::Reset => {
+                                expected = Some(0);
+                                continue;
+                            }
+                            OpusFrame::Frame { seq, data } => (seq, data),
+                        };
+                        samples += self.frames_to_conceal(expected, seq) * FRAME_SIZE;
+                        expected = Some(seq.wrapping_add(1));
+                        if data.is_empty() {
+                            continue;
+                        }
+                        let max_samples = self.sample_rate as usize * MAX_PACKET_DURATION_MS / 1000;
+                        let frame_samples =
+                            opus::packet::get_nb_samples(data, self.sample_rate as u32)
+                                .map_or(max_samples, |frame_samples| {
+                                    frame_samples.min(max_samples)
+                                });
+                        samples += frame_samples;
+                    }
+                }
+            }
+        }
+        samples * self.decoder_kind.bytes_per_sample()
+    }
+
+    /// How many lost frames are concealed before the frame numbered `seq`, when the frame
+    /// numbered `expected` was due. Going backwards starts the sequence over instead
+    fn frames_to_conceal(&self, expected: Option<u16>, seq: u16) -> usize {
+        match expected {
+            Some(expected) if seq > expected => {
+                ((seq - expected) as usize).min(self.max_plc_frames)
+            }
+            _ => 0,
+        }
+    }
+
+    /// How many samples `silence` samples at `voice_rate` last once decoded. Silence is counted
+    /// at the rate the voice data was recorded at
+    fn silence_samples(&self, silence: u16, voice_rate: Option<u16>) -> usize {
+        match voice_rate {
+            Some(rate) if rate != 0 && rate as i32 != self.sample_rate => {
+                (silence as u64 * self.sample_rate as u64 / rate as u64) as usize
+            }
+            _ => silence as usize,
+        }
+    }
+
     pub fn decode(
         &mut self,
         voice_data: SteamVoiceData,
         output_buffer: &mut [u8],
-    ) -> Result<usize, Box<dyn std::error::Error>> {
+    ) -> Result<usize, DecoderError> {
         let mut total = 0;
         for packet in voice_data.packets() {
-            let packet = packet.expect("Coudln't read packet??");
+            let packet = packet.map_err(DecoderError::InvalidPacket)?;
             match packet {
+                // Opus decodes to the rate the decoder was created with whatever the voice data
+                // was recorded at, but a new rate means the sender started over with a new
+                // encoder, whose frames shouldn't be decoded on top of the old one's
                 Packet::SampleRate(rate) => {
-                    if rate != SAMPLE_RATE as u16 {
-                        panic!("Sample rate was something other than {SAMPLE_RATE}!");
+                    if self.voice_rate.is_some_and(|voice_rate| voice_rate != rate) {
+                        log::debug!(
+                            "Voice data changed its sample rate from {}Hz to {rate}Hz",
+                            self.voice_rate.unwrap()
+                        );
+                        self.decoder.reset_state()?;
+                        self.seq = None;
+                        self.stats.resets += 1;
                     }
+                    self.voice_rate = Some(rate);
                 }
                 Packet::OpusPlc(opus) => {
                     let size = self.decode_opus(opus.as_slice(), &mut output_buffer[total..])?;
                     total += size;
-                    if total >= output_buffer.len() {
-                        return Err("InsufficientOutputBuffer".into());
-                    }
                 }
                 Packet::Silence(silence) => {
-                    total += silence as usize * std::mem::size_of::<i16>();
+                    let silence = self.silence_samples(silence, self.voice_rate);
+                    let size = silence * self.decoder_kind.bytes_per_sample();
+                    let Some(silence) = output_buffer.get_mut(total..total + size) else {
+                        return Err(DecoderError::InsufficientOutputBuffer);
+                    };
+                    silence.fill(0);
+                    total += size;
                 }
             }
         }
@@ -122,55 +315,53 @@ impl SteamVoiceDecoder {
 
     fn decode_opus(
         &mut self,
-        mut data: &[u8],
+        data: &[u8],
         output_buffer: &mut [u8],
     ) -> Result<usize, DecoderError> {
         let mut total = 0;
-        while data.len() > 2 {
-            let (len, remainder) = read_u16(data)?;
-            data = remainder;
-            if len == u16::MAX {
-                self.decoder.reset_state()?;
-                self.seq = 0;
-                continue;
-            }
-            let (seq, remainder) = read_u16(data)?;
-            data = remainder;
-
-            if seq < self.seq {
-                self.decoder.reset_state()?;
-            } else {
-                let lost = (seq - self.seq).min(10);
-                for _ in 0..lost {
-                    let count = self.decoder_kind.decode(
-                        &mut self.decoder,
-                        &[],
-                        &mut output_buffer[total..],
-                    )?;
-                    total += count;
-                    if total >= output_buffer.len() {
-                        return Err(DecoderError::InsufficientData);
+        for frame in opus_frames(data)? {
+            let (seq, data) = match frame {
+                OpusFrame::Reset => {
+                    self.decoder.reset_state()?;
+                    self.seq = Some(0);
+                    self.stats.resets += 1;
+                    continue;
+                }
+                OpusFrame::Frame { seq, data } => (seq, data),
+            };
+
+            match self.seq {
+                None => {}
+                Some(expected) if seq < expected => {
+                    self.decoder.reset_state()?;
+                    self.stats.resets += 1;
+                }
+                Some(expected) => {
+                    self.stats.lost_packets += (seq - expected) as usize;
+                    let lost = self.frames_to_conceal(self.seq, seq);
+                    self.stats.concealed_frames += lost;
+                    for _ in 0..lost {
+                        let count = self.decoder_kind.decode(
+                            &mut self.decoder,
+                            &[],
+                            &mut output_buffer[total..],
+                        )?;
+                        total += count;
                     }
                 }
             }
-            let len = len as usize;
-
-            self.seq = seq + 1;
+            self.seq = Some(seq.wrapping_add(1));
 
-            if data.len() < len {
-                return Err(DecoderError::InsufficientData);
+            // An empty input is how opus is asked to conceal a lost frame, but keepalives weren't
+            // lost, so they are skipped like raw opus extraction does
+            if data.is_empty() {
+                continue;
             }
-
-            let count = self.decoder_kind.decode(
-                &mut self.decoder,
-                &data[0..len],
-                &mut output_buffer[total..],
-            )?;
-            data = &data[len..];
+            let count =
+                self.decoder_kind
+                    .decode(&mut self.decoder, data, &mut output_buffer[total..])?;
             total += count;
-            if total >= output_buffer.len() {
-                return Err(DecoderError::InsufficientData);
-            }
+            self.stats.packets += 1;
         }
 
         Ok(total)
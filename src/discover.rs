@@ -0,0 +1,264 @@
+use dem::types::{
+    Demo, EngineMessage, Frame, FrameData, MessageData, NetMessage, SvcUpdateUserInfo,
+    SvcVoiceData, SvcVoiceInit,
+};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use steam_audio_codec::{Packet, SteamAudioError, SteamVoiceData};
+
+use crate::SAMPLE_RATE;
+
+/// Voice activity of a single player across a demo
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerActivity {
+    /// Number of `svc_voicedata` messages attributed to the player
+    pub message_count: usize,
+    /// Demo time of the first voice message, in seconds
+    pub first_time: f32,
+    /// Demo time of the last voice message, in seconds
+    pub last_time: f32,
+}
+
+/// Voice settings announced by a demo
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoiceFormat {
+    /// Codec named by `svc_voiceinit`, if the demo has one
+    pub codec_name: Option<String>,
+    /// Codec quality named by `svc_voiceinit`, if the demo has one
+    pub quality: Option<i8>,
+    /// Sample rate announced by the first steam voice data carrying one, or [`SAMPLE_RATE`] if
+    /// none does. `svc_voiceinit` itself doesn't carry a rate
+    pub sample_rate: i32,
+}
+
+/// Iterates over every frame of the demo that is played back, skipping the startup segment
+pub(crate) fn playback_frames(demo: &Demo) -> impl Iterator<Item = &Frame> {
+    demo.directory
+        .entries
+        .iter()
+        .filter(|entry| entry.type_ != 0) // nEntryType == DEMO_STARTUP
+        .flat_map(|entry| &entry.frames)
+}
+
+/// Like [`playback_frames`], but takes the demo apart so every frame is dropped once it has been
+/// iterated over
+pub(crate) fn into_playback_frames(demo: Demo) -> impl Iterator<Item = Frame> {
+    demo.directory
+        .entries
+        .into_iter()
+        .filter(|entry| entry.type_ != 0)
+        .flat_map(|entry| entry.frames)
+}
+
+/// Parses a demo that is already in memory, for callers that never have it on disk
+pub fn parse_demo(bytes: &[u8]) -> Result<Demo, Box<dyn std::error::Error>> {
+    Ok(dem::open_demo_from_bytes(bytes).map_err(|e| e.to_string())?)
+}
+
+/// Parses a demo out of `reader`, such as an open file or a download. `dem` needs the whole demo
+/// at once, so it is read into memory first and dropped again once parsed
+pub fn read_demo(mut reader: impl Read) -> Result<Demo, Box<dyn std::error::Error>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    parse_demo(&bytes)
+}
+
+/// Appends every demo of `rest` to `demo`, in order, as if all of them were recorded in one go.
+///
+/// The frames of each demo are shifted to start where the playback of the previous one ended, so
+/// extracting the result gives a single timeline, with each steam id in one continuous stream.
+/// Nothing else is rewritten; decoders carry on or reset based on the sequence numbers they see,
+/// the same as within a single demo.
+pub fn concat_demos(mut demo: Demo, rest: impl IntoIterator<Item = Demo>) -> Demo {
+    for next in rest {
+        let offset = playback_frames(&demo)
+            .map(|frame| frame.time)
+            .fold(0.0, f32::max);
+        for mut entry in next.directory.entries {
+            for frame in &mut entry.frames {
+                frame.time += offset;
+            }
+            demo.directory.entries.push(entry);
+        }
+    }
+    demo
+}
+
+/// Iterates over the engine messages carried by a single demo frame
+fn engine_messages(frame: &Frame) -> impl Iterator<Item = &EngineMessage> {
+    let messages = match &frame.frame_data {
+        FrameData::NetworkMessage(boxed_network_message) => match &boxed_network_message.1.messages
+        {
+            MessageData::Parsed(messages) => messages.as_slice(),
+            _ => &[],
+        },
+        _ => &[],
+    };
+    messages.iter().filter_map(|message| match message {
+        NetMessage::EngineMessage(engine_message) => Some(&**engine_message),
+        _ => None,
+    })
+}
+
+/// Iterates over the voice data messages carried by a single demo frame
+pub(crate) fn voice_messages(frame: &Frame) -> impl Iterator<Item = &SvcVoiceData> {
+    engine_messages(frame).filter_map(|engine_message| match engine_message {
+        EngineMessage::SvcVoiceData(svc_voice_data) => Some(svc_voice_data),
+        _ => None,
+    })
+}
+
+/// Parses the steam voice data header of a voice message.
+///
+/// `SteamVoiceData::new` panics on payloads too short to hold the trailing CRC, so those are
+/// rejected here first.
+pub(crate) fn parse_voice_data(data: &[u8]) -> Result<SteamVoiceData<'_>, SteamAudioError> {
+    if data.len() < 4 {
+        return Err(SteamAudioError::InsufficientData);
+    }
+    SteamVoiceData::new(data)
+}
+
+/// Reads the voice codec announced by `svc_voiceinit` and the sample rate the voice data is
+/// encoded at, without decoding anything
+pub fn voice_format(demo: &Demo) -> VoiceFormat {
+    let init = demo
+        .directory
+        .entries
+        .iter()
+        .flat_map(|entry| &entry.frames)
+        .flat_map(engine_messages)
+        .find_map(|engine_message| match engine_message {
+            EngineMessage::SvcVoiceInit(SvcVoiceInit {
+                codec_name,
+                quality,
+            }) => Some((codec_name, *quality)),
+            _ => None,
+        });
+    let sample_rate = playback_frames(demo)
+        .flat_map(voice_messages)
+        .filter_map(|svc_voice_data| parse_voice_data(&svc_voice_data.data).ok())
+        .find_map(|steam_voice_data| {
+            steam_voice_data.packets().find_map(|packet| match packet {
+                Ok(Packet::SampleRate(rate)) => Some(rate as i32),
+                _ => None,
+            })
+        })
+        .unwrap_or(SAMPLE_RATE);
+
+    VoiceFormat {
+        codec_name: init.map(|(codec_name, _)| {
+            let codec_name = codec_name.split(|&b| b == 0).next().unwrap_or_default();
+            String::from_utf8_lossy(codec_name).into_owned()
+        }),
+        quality: init.map(|(_, quality)| quality),
+        sample_rate,
+    }
+}
+
+/// Finds every player with voice data in the demo, keyed by steam id, without decoding anything
+pub fn scan_players(demo: &Demo) -> HashMap<u64, PlayerActivity> {
+    let mut players: HashMap<u64, PlayerActivity> = HashMap::new();
+    for frame in playback_frames(demo) {
+        for svc_voice_data in voice_messages(frame) {
+            // Malformed messages are reported when decoding, a bad packet shouldn't stop the scan
+            let Ok(steam_voice_data) = parse_voice_data(&svc_voice_data.data) else {
+                continue;
+            };
+
+            players
+                .entry(steam_voice_data.steam_id)
+                .and_modify(|activity| {
+                    activity.message_count += 1;
+                    activity.last_time = frame.time;
+                })
+                .or_insert(PlayerActivity {
+                    message_count: 1,
+                    first_time: frame.time,
+                    last_time: frame.time,
+                });
+        }
+    }
+    players
+}
+
+/// Looks up `key` in a `\key\value\key\value` userinfo string
+fn userinfo_value<'a>(user_info: &'a str, key: &str) -> Option<&'a str> {
+    let mut parts = user_info.strip_prefix('\\')?.split('\\');
+    while let (Some(k), Some(value)) = (parts.next(), parts.next()) {
+        if k == key {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Builds a map of steam id to in-game name from the userinfo updates in the demo.
+///
+/// Players are matched by the `*sid` userinfo key when present, and otherwise by the client slot
+/// their voice data is sent from, which never overrides a name from `*sid` since slots are reused.
+/// When a player renames mid-demo, the last name seen wins.
+pub fn resolve_names(demo: &Demo) -> HashMap<u64, String> {
+    let mut names = HashMap::new();
+    let mut slot_names: HashMap<u8, String> = HashMap::new();
+    // Steam id of the player in every slot according to their userinfo, and every player named
+    // that way
+    let mut slot_steam_ids: HashMap<u8, u64> = HashMap::new();
+    let mut by_sid = HashSet::new();
+    let frames = demo
+        .directory
+        .entries
+        .iter()
+        .flat_map(|entry| &entry.frames);
+    for frame in frames {
+        for engine_message in engine_messages(frame) {
+            match engine_message {
+                EngineMessage::SvcUpdateUserInfo(SvcUpdateUserInfo {
+                    index, user_info, ..
+                }) => {
+                    let Ok(user_info) = user_info.to_str() else {
+                        continue;
+                    };
+                    let Some(name) = userinfo_value(user_info, "name") else {
+                        continue;
+                    };
+                    match userinfo_value(user_info, "*sid").and_then(|sid| sid.parse().ok()) {
+                        Some(steam_id) => {
+                            names.insert(steam_id, name.to_string());
+                            by_sid.insert(steam_id);
+                            slot_steam_ids.insert(*index, steam_id);
+                        }
+                        None => {
+                            slot_steam_ids.remove(index);
+                        }
+                    }
+                    slot_names.insert(*index, name.to_string());
+                }
+                EngineMessage::SvcVoiceData(svc_voice_data) => {
+                    let Some(name) = slot_names.get(&svc_voice_data.player_index) else {
+                        continue;
+                    };
+                    let Ok(steam_voice_data) = parse_voice_data(&svc_voice_data.data) else {
+                        continue;
+                    };
+                    let steam_id = steam_voice_data.steam_id;
+                    let slot = svc_voice_data.player_index;
+                    if let Some(&sid) = slot_steam_ids.get(&slot)
+                        && sid != steam_id
+                    {
+                        log::debug!(
+                            "Voice of {steam_id} comes from slot {slot}, which userinfo says {sid} \
+                             is in, not naming them after it"
+                        );
+                        continue;
+                    }
+                    if !by_sid.contains(&steam_id) {
+                        names.insert(steam_id, name.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    names
+}
@@ -0,0 +1,80 @@
+use std::f64::consts::PI;
+
+use crate::{SAMPLE_FMT_FLT, SAMPLE_FMT_S16};
+
+/// Direct form 1 biquad
+pub struct Biquad {
+    pub b: [f64; 3],
+    pub a: [f64; 2],
+    pub x: [f64; 2],
+    pub y: [f64; 2],
+}
+
+impl Biquad {
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+
+    pub fn reset(&mut self) {
+        self.x = [0.0; 2];
+        self.y = [0.0; 2];
+    }
+}
+
+/// Second order Butterworth high-pass over the samples of a single player, to take out the
+/// rumble a lot of microphones pick up
+pub struct HighPass {
+    filter: Biquad,
+}
+
+impl HighPass {
+    pub fn new(cutoff: f32, sample_rate: i32) -> Self {
+        let k = (PI * cutoff as f64 / sample_rate as f64).tan();
+        let q = std::f64::consts::FRAC_1_SQRT_2;
+        let a0 = 1.0 + k / q + k * k;
+        Self {
+            filter: Biquad {
+                b: [1.0 / a0, -2.0 / a0, 1.0 / a0],
+                a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+                x: [0.0; 2],
+                y: [0.0; 2],
+            },
+        }
+    }
+
+    /// Filters `samples` in `sample_format` (s16 or float) in place
+    pub fn process(&mut self, samples: &mut [u8], sample_format: i32) {
+        match sample_format {
+            SAMPLE_FMT_S16 => {
+                for sample in samples.chunks_exact_mut(2) {
+                    let x = i16::from_ne_bytes([sample[0], sample[1]]) as f64;
+                    let y = self
+                        .filter
+                        .process(x)
+                        .round()
+                        .clamp(i16::MIN as f64, i16::MAX as f64);
+                    sample.copy_from_slice(&(y as i16).to_ne_bytes());
+                }
+            }
+            SAMPLE_FMT_FLT => {
+                for sample in samples.chunks_exact_mut(4) {
+                    let x = f32::from_ne_bytes(sample.try_into().unwrap()) as f64;
+                    let y = self.filter.process(x).clamp(-1.0, 1.0);
+                    sample.copy_from_slice(&(y as f32).to_ne_bytes());
+                }
+            }
+            _ => panic!("filtering samples in a sample format that we didn't account for!"),
+        }
+    }
+
+    /// Forgets the previous samples, so the next burst of speech doesn't pick up where the last
+    /// one left off
+    pub fn reset(&mut self) {
+        self.filter.reset();
+    }
+}
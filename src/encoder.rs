@@ -0,0 +1,429 @@
+use rsmpeg::{
+    avcodec::{AVCodecContext, AVCodecRef, AVPacket},
+    avformat::AVFormatContextOutput,
+    avutil::{
+        AVChannelLayout, AVDictionary, AVFrame, get_bytes_per_sample, get_packed_sample_fmt,
+        get_sample_fmt_name, opt_set, sample_fmt_is_planar,
+    },
+    ffi::{
+        self, AV_CODEC_CONFIG_SAMPLE_RATE, AV_SAMPLE_FMT_DBL, AV_SAMPLE_FMT_FLT, AV_SAMPLE_FMT_S16,
+        AVRational,
+    },
+    swresample::SwrContext,
+};
+use std::ffi::CString;
+use std::ops::Range;
+
+use crate::ExtractConfig;
+
+/// Encodes the decoded samples of a single player with ffmpeg
+pub struct Encoder {
+    pub codec_ctx: AVCodecContext,
+    pub stream_index: usize,
+    pub frame: AVFrame,
+    /// Samples the encoder takes in every frame but the last
+    pub frame_size: usize,
+    /// Samples in the encoder's format that don't fill a whole frame yet, one buffer per plane
+    pub frame_accum: Vec<Vec<u8>>,
+    pub pts: i64,
+    /// Sample format the voice decoder has to produce for this encoder
+    pub decoder_format: i32,
+    pub bytes_per_sample: usize,
+    pub enc_bytes_per_sample: usize,
+    /// Number of interleaved channels in the samples passed to [`Encoder::encode`]
+    pub channels: usize,
+    /// Number of planes the encoder takes its samples in, one per channel for planar formats
+    pub planes: usize,
+    pub resampler: Option<SwrContext>,
+    /// Container owned by this player when outputs are split, `None` when muxing into a shared one
+    pub fmt_ctx: Option<AVFormatContextOutput>,
+    /// Packets encoded while there was nowhere to write them, still in the codec's time base.
+    /// The caller muxes these into the shared container once encoding is done
+    pub queued_packets: Vec<AVPacket>,
+}
+
+impl Encoder {
+    /// Adds a stream titled `title` to `fmt_ctx`, encoding `channels` interleaved channels of
+    /// samples decoded at `sample_rate` with the encoder and options of `config`
+    pub fn new(
+        fmt_ctx: &mut AVFormatContextOutput,
+        config: &ExtractConfig,
+        title: &str,
+        sample_rate: i32,
+        channels: usize,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let codec: &AVCodecRef<'static> = config
+            .codec
+            .as_ref()
+            .ok_or("An encoder is required to write through ffmpeg")?;
+        let mut codec_ctx = AVCodecContext::new(codec);
+        let format_name = |format| {
+            get_sample_fmt_name(format).map_or("unknown".into(), |name| name.to_string_lossy())
+        };
+
+        let channel_layout = AVChannelLayout::from_nb_channels(channels as i32).into_inner();
+        let supported_fmts = codec_ctx.get_supported_sample_fmts(None)?;
+
+        let (decoder_format, encoder_format) = if let Some(format) = config.encoder_format {
+            if !supported_fmts.is_empty() && !supported_fmts.contains(&format) {
+                let supported: Vec<_> = supported_fmts
+                    .iter()
+                    .map(|&format| format_name(format))
+                    .collect();
+                return Err(format!(
+                    "{} doesn't support {}, only {}",
+                    codec.name().to_string_lossy(),
+                    format_name(format),
+                    supported.join(", ")
+                )
+                .into());
+            }
+            // Floating point formats are decoded to float so no precision is lost on the way
+            let float = [AV_SAMPLE_FMT_FLT, AV_SAMPLE_FMT_DBL]
+                .contains(&get_packed_sample_fmt(format).unwrap_or(format));
+            let decoder_format = if float {
+                AV_SAMPLE_FMT_FLT
+            } else {
+                AV_SAMPLE_FMT_S16
+            };
+            (decoder_format, format)
+        } else if supported_fmts.contains(&AV_SAMPLE_FMT_S16) {
+            (AV_SAMPLE_FMT_S16, AV_SAMPLE_FMT_S16)
+        } else if supported_fmts.contains(&AV_SAMPLE_FMT_FLT) {
+            (AV_SAMPLE_FMT_FLT, AV_SAMPLE_FMT_FLT)
+        } else {
+            let encoder_format = supported_fmts
+                .first()
+                .copied()
+                .ok_or("Encoder does not report supported sample formats?")?;
+            (AV_SAMPLE_FMT_S16, encoder_format)
+        };
+
+        let supported_rates =
+            unsafe { codec_ctx.get_supported_config::<i32>(None, AV_CODEC_CONFIG_SAMPLE_RATE) }?;
+        let encoder_rate = if let Some(rate) = config.encoder_rate {
+            if !supported_rates.is_empty() && !supported_rates.contains(&rate) {
+                let supported: Vec<String> = supported_rates.iter().map(i32::to_string).collect();
+                return Err(format!(
+                    "{} doesn't support {rate}Hz, only {}Hz",
+                    codec.name().to_string_lossy(),
+                    supported.join(", ")
+                )
+                .into());
+            }
+            rate
+        } else if supported_rates.is_empty() || supported_rates.contains(&sample_rate) {
+            sample_rate
+        } else {
+            supported_rates
+                .first()
+                .copied()
+                .expect("Coudln't get first supported rate?")
+        };
+
+        let resampler = if decoder_format != encoder_format || sample_rate != encoder_rate {
+            log::debug!(
+                "Resampling {title} from {} at {sample_rate}Hz to {} at {encoder_rate}Hz",
+                format_name(decoder_format),
+                format_name(encoder_format),
+            );
+            let mut swr = SwrContext::new(
+                &channel_layout,
+                encoder_format,
+                encoder_rate,
+                &channel_layout,
+                decoder_format,
+                sample_rate,
+            )?;
+            for (key, value) in &config.resample_options {
+                let (key, value) = (CString::new(key.as_str())?, CString::new(value.as_str())?);
+                // SAFETY: SwrContext starts with its AVClass pointer, like every ffmpeg context
+                unsafe { opt_set(swr.as_mut_ptr().cast(), &key, &value, 0) }
+                    .map_err(|e| format!("Invalid resampler option {key:?}: {e}"))?;
+            }
+            swr.init()?;
+            Some(swr)
+        } else {
+            None
+        };
+
+        codec_ctx.set_sample_fmt(encoder_format);
+        codec_ctx.set_ch_layout(channel_layout);
+        codec_ctx.set_sample_rate(encoder_rate);
+        codec_ctx.set_time_base(AVRational {
+            num: 1,
+            den: codec_ctx.sample_rate,
+        });
+        if let Some(bitrate) = config.bitrate {
+            codec_ctx.set_bit_rate(bitrate);
+        }
+
+        let mut dict: Option<AVDictionary> = None;
+        for (key, value) in &config.codec_options {
+            let (key, value) = (CString::new(key.as_str())?, CString::new(value.as_str())?);
+            dict = Some(match dict {
+                Some(dict) => dict.set(&key, &value, 0),
+                None => AVDictionary::new(&key, &value, 0),
+            });
+        }
+        // Whatever is left over wasn't an option of the encoder
+        if let Some(unused) = codec_ctx.open(dict)? {
+            for entry in &unused {
+                log::warn!(
+                    "{} has no option {:?}, ignoring it",
+                    codec.name().to_string_lossy(),
+                    entry.key()
+                );
+            }
+        }
+        log::debug!(
+            "Opened a {} encoder for {title}: {} at {encoder_rate}Hz with {channels} channel(s)",
+            codec.name().to_string_lossy(),
+            format_name(encoder_format),
+        );
+
+        let stream_index = {
+            let mut stream = fmt_ctx.new_stream();
+            stream.set_codecpar(codec_ctx.extract_codecpar());
+            stream.set_time_base(codec_ctx.time_base);
+            stream.set_metadata(Some(AVDictionary::new(c"title", &CString::new(title)?, 0)));
+            stream.index as usize
+        };
+
+        let mut frame = AVFrame::new();
+        let frame_size = if codec_ctx.frame_size > 0 {
+            codec_ctx.frame_size
+        } else {
+            1024
+        };
+        frame.set_nb_samples(frame_size);
+        frame.set_format(codec_ctx.sample_fmt);
+        frame.set_ch_layout(codec_ctx.ch_layout);
+        frame.set_sample_rate(codec_ctx.sample_rate);
+        frame.get_buffer(0)?;
+        let planes = if sample_fmt_is_planar(encoder_format) {
+            channels
+        } else {
+            1
+        };
+
+        Ok(Self {
+            codec_ctx,
+            stream_index,
+            frame,
+            frame_size: frame_size as usize,
+            frame_accum: vec![Vec::new(); planes],
+            pts: 0,
+            decoder_format,
+            bytes_per_sample: get_bytes_per_sample(decoder_format)
+                .expect("Couldn't get bytes per sample of sample format???"),
+            enc_bytes_per_sample: get_bytes_per_sample(encoder_format)
+                .expect("Coudln't get bytes per sample on encoder format?"),
+            resampler,
+            channels,
+            planes,
+            fmt_ctx: None,
+            queued_packets: Vec::new(),
+        })
+    }
+
+    /// Encodes `samples` (in the decoder's sample format) and writes out every packet that is
+    /// ready. Samples that don't fill a whole encoder frame are held until the next call.
+    pub fn encode(
+        &mut self,
+        new_samples: &[u8],
+        muxer: Option<&mut AVFormatContextOutput>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.resampler.is_some() {
+            let in_count = new_samples.len() / (self.bytes_per_sample * self.channels);
+            let in_bufs = [new_samples.as_ptr()];
+            // SAFETY: the single packed input plane holds `in_count` samples of every channel
+            unsafe { self.resample(in_bufs.as_ptr(), in_count as i32) }?;
+        } else {
+            self.frame_accum[0].extend_from_slice(new_samples);
+        }
+        self.encode_frames(false, muxer)
+    }
+
+    /// Converts `in_count` samples through the resampler onto the end of
+    /// [`Encoder::frame_accum`]. A null `input` drains what the resampler still holds instead,
+    /// returning how many samples came out
+    unsafe fn resample(
+        &mut self,
+        input: *const *const u8,
+        in_count: i32,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let resampler = self
+            .resampler
+            .as_mut()
+            .expect("Resampling without a resampler");
+        let out_count = resampler.get_out_samples(in_count);
+        let sample_bytes = self.enc_bytes_per_sample * self.channels / self.planes;
+        let mut out_bufs: Vec<*mut u8> = self
+            .frame_accum
+            .iter_mut()
+            .map(|plane| {
+                let len = plane.len();
+                plane.resize(len + out_count as usize * sample_bytes, 0);
+                plane[len..].as_mut_ptr()
+            })
+            .collect();
+
+        let out_samples =
+            unsafe { resampler.convert(out_bufs.as_mut_ptr(), out_count, input, in_count)? }
+                as usize;
+
+        let unused = (out_count as usize - out_samples) * sample_bytes;
+        for plane in &mut self.frame_accum {
+            plane.truncate(plane.len() - unused);
+        }
+        Ok(out_samples)
+    }
+
+    /// Sends every whole frame of [`Encoder::frame_accum`] to the encoder. With `last`, whatever
+    /// is left over is sent as a final, shorter frame
+    fn encode_frames(
+        &mut self,
+        last: bool,
+        mut muxer: Option<&mut AVFormatContextOutput>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let sample_bytes = self.enc_bytes_per_sample * self.channels / self.planes;
+        let frame_bytes = self.frame_size * sample_bytes;
+
+        let mut offset = 0;
+        loop {
+            let available = self.frame_accum[0].len() - offset;
+            let len = match available {
+                0 => break,
+                _ if available >= frame_bytes => frame_bytes,
+                _ if last => available,
+                _ => break,
+            };
+
+            // The encoder may still hold a reference to the previous frame's buffers
+            self.frame.make_writable()?;
+            for (plane, samples) in self.frame_accum.iter().enumerate() {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        samples[offset..].as_ptr(),
+                        self.frame.data[plane],
+                        len,
+                    );
+                }
+            }
+            let nb_samples = len / sample_bytes;
+            self.frame.set_nb_samples(nb_samples as i32);
+            self.frame.set_pts(self.pts);
+            self.pts += nb_samples as i64;
+            self.codec_ctx.send_frame(Some(&self.frame))?;
+            self.write_packets(muxer.as_deref_mut())?;
+
+            offset += len;
+        }
+
+        for plane in &mut self.frame_accum {
+            plane.drain(..offset);
+        }
+        Ok(())
+    }
+
+    /// Encodes whatever is still held back and flushes the encoder, then writes the trailer of
+    /// this player's own container if it has one
+    pub fn finish(
+        &mut self,
+        mut muxer: Option<&mut AVFormatContextOutput>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.resampler.is_some() {
+            // SAFETY: a null input only drains the resampler
+            while unsafe { self.resample(std::ptr::null(), 0) }? > 0 {}
+        }
+        self.encode_frames(true, muxer.as_deref_mut())?;
+        self.codec_ctx.send_frame(None)?;
+        self.write_packets(muxer)?;
+
+        if let Some(fmt_ctx) = self.fmt_ctx.as_mut() {
+            fmt_ctx.write_trailer()?;
+        }
+        Ok(())
+    }
+
+    /// Writes every packet the encoder has ready into this player's own container, or into
+    /// `muxer` if the player doesn't have one. Without either, the packets are queued in
+    /// [`Encoder::queued_packets`].
+    fn write_packets(
+        &mut self,
+        muxer: Option<&mut AVFormatContextOutput>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let fmt_ctx = match (self.fmt_ctx.as_mut(), muxer) {
+            (Some(fmt_ctx), _) | (None, Some(fmt_ctx)) => fmt_ctx,
+            (None, None) => {
+                while let Ok(mut pkt) = self.codec_ctx.receive_packet() {
+                    pkt.set_stream_index(self.stream_index as i32);
+                    self.queued_packets.push(pkt);
+                }
+                return Ok(());
+            }
+        };
+        while let Ok(mut pkt) = self.codec_ctx.receive_packet() {
+            pkt.rescale_ts(
+                self.codec_ctx.time_base,
+                fmt_ctx.streams()[self.stream_index].time_base,
+            );
+            pkt.set_stream_index(self.stream_index as i32);
+            fmt_ctx.write_frame(&mut pkt)?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds a chapter titled after the player for every burst of speech to `fmt_ctx`. Formats that
+/// carry chapters write them along with the trailer, so this has to happen before it is written.
+/// `speech` is ranges of samples at `sample_rate`, in the order the chapters are numbered
+pub fn add_chapters<'a>(
+    fmt_ctx: &mut AVFormatContextOutput,
+    speech: impl IntoIterator<Item = (&'a Range<i64>, &'a str)>,
+    sample_rate: i32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (range, title) in speech {
+        let title = CString::new(title)?;
+        // SAFETY: the chapter is allocated the way libavformat frees it along with the context,
+        // and the context's chapter array is only grown through av_dynarray_add
+        unsafe {
+            let ctx = fmt_ctx.as_mut_ptr();
+            let chapter =
+                ffi::av_mallocz(std::mem::size_of::<ffi::AVChapter>()).cast::<ffi::AVChapter>();
+            if chapter.is_null() {
+                return Err("Couldn't allocate a chapter".into());
+            }
+            // Chapter ids are used as unique ids by some formats, which can't be 0
+            (*chapter).id = (*ctx).nb_chapters as i64 + 1;
+            (*chapter).time_base = AVRational {
+                num: 1,
+                den: sample_rate,
+            };
+            (*chapter).start = range.start;
+            (*chapter).end = range.end;
+            if ffi::av_dict_set(
+                &mut (*chapter).metadata,
+                c"title".as_ptr(),
+                title.as_ptr(),
+                0,
+            ) < 0
+            {
+                ffi::av_free(chapter.cast());
+                return Err("Couldn't set the title of a chapter".into());
+            }
+            let count = (*ctx).nb_chapters;
+            ffi::av_dynarray_add(
+                (&raw mut (*ctx).chapters).cast(),
+                (&raw mut (*ctx).nb_chapters).cast(),
+                chapter.cast(),
+            );
+            if (*ctx).nb_chapters == count {
+                ffi::av_free(chapter.cast());
+                return Err("Couldn't add a chapter to the output".into());
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,1137 @@
+//! Extracts voice chat data from GoldSrc demo files into a multi-stream audio container.
+//!
+//! The entry point is [`extract`], which takes an already parsed [`Demo`] and writes one audio
+//! stream per speaking player, either muxed into a single container or into a container per
+//! player (see [`Output`]). [`Output::Samples`] hands the decoded samples of every player back
+//! instead of writing anything. [`extract_raw_opus`] copies the voice data into Ogg Opus files
+//! as it was sent, without decoding it.
+//!
+//! Demos on disk can be parsed with `dem::open_demo`. Demos that only live in memory, such as
+//! downloads, are parsed with [`parse_demo`] from bytes or [`read_demo`] from any reader.
+//!
+//! Every output follows the demo's clock: a player's stream starts at the start of the demo (or
+//! [`ExtractConfig::start`]) and is filled with silence whenever they aren't talking, so all of
+//! them line up sample for sample. Like the engine, decoded voice is only played back once some of
+//! it has been buffered (see [`ExtractConfig::jitter_buffer`]), and then at the pace of demo time
+//! rather than as fast as it was received.
+//!
+//! # Memory
+//!
+//! `dem` parses the whole demo up front, so the parsed [`Demo`] is the bulk of the memory used.
+//! [`extract`] only borrows it, so all of it stays around until extraction is done, on top of
+//! whatever extraction itself holds. [`extract_owned`] makes the same passes over the whole demo
+//! first (discovering players, resolving names, measuring loudness), but then frees every frame as
+//! soon as it has been played back: memory peaks right after parsing and goes down from there,
+//! rather than staying at the size of the demo throughout.
+//!
+//! Beyond the demo, only about a demo frame of samples is held per player during playback, unless
+//! [`ExtractConfig::jobs`] buffers every player's timeline or [`Output::Samples`] keeps it. Those
+//! grow with the output, which [`extract_owned`] makes room for as the demo shrinks.
+//!
+//! Encoding through ffmpeg is behind the default `ffmpeg` feature. Without it, only the native
+//! WAV outputs are available and nothing links against ffmpeg.
+use dem::types::{Demo, Frame};
+#[cfg(feature = "ffmpeg")]
+use rsmpeg::{avcodec::AVCodecRef, avformat::AVFormatContextOutput};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufWriter;
+use std::num::NonZeroUsize;
+use std::ops::Range;
+
+mod check;
+mod csv;
+mod cue;
+pub mod decoder;
+mod discover;
+mod dsp;
+#[cfg(feature = "ffmpeg")]
+mod encoder;
+pub mod json;
+mod loudness;
+mod ogg;
+mod progress;
+mod raw_opus;
+mod srt;
+mod stream;
+mod trim;
+mod wav;
+
+pub use check::{PlayerCheck, VoiceCheck, check_voice};
+pub use csv::write_speech_csv;
+pub use cue::write_cue;
+use decoder::{DecoderStats, SteamVoiceDecoder};
+pub use discover::{
+    PlayerActivity, VoiceFormat, concat_demos, parse_demo, read_demo, resolve_names, scan_players,
+    voice_format,
+};
+use discover::{into_playback_frames, parse_voice_data, playback_frames, voice_messages};
+#[cfg(feature = "ffmpeg")]
+use encoder::Encoder;
+use loudness::LoudnessMeter;
+use progress::Progress;
+pub use raw_opus::extract_raw_opus;
+pub use srt::{SrtCue, speech_cues, write_srt, write_vtt};
+use stream::{Mixer, PlayerStream, Sink, mix_samples};
+use trim::SilenceTrimmer;
+use wav::WavWriter;
+
+/// Sample rate of steam voice data, assumed when a demo's voice data doesn't announce one
+pub const SAMPLE_RATE: i32 = 24_000;
+
+/// Signed 16-bit samples, the same value as ffmpeg's `AV_SAMPLE_FMT_S16`
+pub const SAMPLE_FMT_S16: i32 = 1;
+/// 32-bit float samples, the same value as ffmpeg's `AV_SAMPLE_FMT_FLT`
+pub const SAMPLE_FMT_FLT: i32 = 3;
+
+#[cfg(feature = "ffmpeg")]
+const _: () = assert!(
+    SAMPLE_FMT_S16 == rsmpeg::ffi::AV_SAMPLE_FMT_S16
+        && SAMPLE_FMT_FLT == rsmpeg::ffi::AV_SAMPLE_FMT_FLT
+);
+
+/// Size of a single sample in one of the sample formats above
+pub(crate) fn bytes_per_sample(sample_format: i32) -> usize {
+    match sample_format {
+        SAMPLE_FMT_S16 => std::mem::size_of::<i16>(),
+        SAMPLE_FMT_FLT => std::mem::size_of::<f32>(),
+        _ => panic!("sample format that we didn't account for!"),
+    }
+}
+
+/// Creates the container of a single player from their steam id and display name
+#[cfg(feature = "ffmpeg")]
+pub type OutputFactory<'a> =
+    dyn FnMut(u64, &str) -> Result<AVFormatContextOutput, Box<dyn std::error::Error>> + 'a;
+
+/// Creates the WAV, raw PCM or Ogg file of a single player from their steam id and display name
+pub type WavFactory<'a> = dyn FnMut(u64, &str) -> std::io::Result<File> + 'a;
+
+/// Where the encoded player streams are written
+pub enum Output<'a> {
+    /// Every player is written as a separate stream of one shared container
+    #[cfg(feature = "ffmpeg")]
+    Muxed(&'a mut AVFormatContextOutput),
+    /// Every player gets its own container, created from their steam id and display name (see
+    /// [`resolve_names`]) when they are discovered.
+    ///
+    /// Silence is still written while a player isn't talking, so every container spans the whole
+    /// demo and the files line up when played back together.
+    #[cfg(feature = "ffmpeg")]
+    Split(&'a mut OutputFactory<'a>),
+    /// Every player is written as one channel of a single 16-bit PCM WAV file, ordered by steam
+    /// id, without going through ffmpeg
+    Wav(File),
+    /// Every player gets their own 16-bit PCM WAV file, written without going through ffmpeg.
+    /// Silence is still written while a player isn't talking, so the files line up when played
+    /// back together
+    WavSplit(&'a mut WavFactory<'a>),
+    /// Like [`Output::Wav`], but as bare interleaved samples in the given sample format
+    /// ([`SAMPLE_FMT_S16`] or [`SAMPLE_FMT_FLT`]) without any header
+    Pcm(File, i32),
+    /// Like [`Output::WavSplit`], but as bare samples in the given sample format without any
+    /// header
+    PcmSplit(&'a mut WavFactory<'a>, i32),
+    /// Nothing is written, the mono samples of every player are kept in memory in the given
+    /// sample format and returned in [`PlayerSummary::samples`]. Can't be mixed down
+    Samples(i32),
+    /// Nothing is written or kept, every player is still decoded and played back. For checking
+    /// that a demo extracts cleanly, and what its [`ExtractSummary`] would be. Mixing is skipped
+    Discard,
+}
+
+/// Which players get extracted. Voice data of filtered out players is skipped without being
+/// decoded
+#[derive(Debug, Clone, Default)]
+pub enum PlayerFilter {
+    /// Every player with voice data
+    #[default]
+    All,
+    /// Only the players with these steam ids
+    Only(HashSet<u64>),
+    /// Everyone except the players with these steam ids
+    Exclude(HashSet<u64>),
+}
+
+impl PlayerFilter {
+    pub fn allows(&self, steam_id: u64) -> bool {
+        match self {
+            PlayerFilter::All => true,
+            PlayerFilter::Only(steam_ids) => steam_ids.contains(&steam_id),
+            PlayerFilter::Exclude(steam_ids) => !steam_ids.contains(&steam_id),
+        }
+    }
+}
+
+/// How silence is trimmed out of every player's stream
+#[derive(Debug, Clone)]
+pub struct TrimSilence {
+    /// Seconds of silence kept before and after speech, so it isn't clipped
+    pub padding: f32,
+    /// Gaps between speech longer than this many seconds are cut down to the padding. Only
+    /// leading and trailing silence is trimmed if `None`
+    pub max_gap: Option<f32>,
+    /// Samples at or below this level in dBFS count as silence too. Only digital silence does if
+    /// `None`
+    pub threshold: Option<f32>,
+}
+
+/// Default for [`ExtractConfig::jitter_buffer`], the same as the engine's
+pub const DEFAULT_JITTER_BUFFER_SECONDS: f32 = 0.2;
+
+/// Settings for a single extraction run
+pub struct ExtractConfig {
+    /// Encoder used for every player stream. Required unless the output is written natively as
+    /// WAV
+    #[cfg(feature = "ffmpeg")]
+    pub codec: Option<AVCodecRef<'static>>,
+    /// Audio bitrate for encoder (when relevant)
+    pub bitrate: Option<i64>,
+    /// Options of the encoder or its codec context, as ffmpeg takes them. Options the encoder
+    /// doesn't know are warned about
+    #[cfg(feature = "ffmpeg")]
+    pub codec_options: Vec<(String, String)>,
+    /// Options of the resampler (swresample) used when the encoder takes another sample format
+    /// or rate than voice is decoded at, such as `filter_size` or `dither_method`
+    #[cfg(feature = "ffmpeg")]
+    pub resample_options: Vec<(String, String)>,
+    /// Sample rate of the encoded audio, resampled from the decoding rate. The encoder has to
+    /// support it; `None` keeps the decoding rate, or the encoder's first rate if it doesn't
+    /// support that one
+    #[cfg(feature = "ffmpeg")]
+    pub encoder_rate: Option<i32>,
+    /// Sample format (`AVSampleFormat`) the encoder is given, converted from the decoded voice.
+    /// The encoder has to support it; `None` prefers s16, then float, then the encoder's first
+    /// format
+    #[cfg(feature = "ffmpeg")]
+    pub encoder_format: Option<i32>,
+    /// Adds a chapter for every burst of speech, titled after the player, to every container
+    /// written with ffmpeg. A shared container gets the speech of every player, a player's own
+    /// container just theirs. Only some formats (like Matroska and MP4) carry chapters, the rest
+    /// ignore them
+    #[cfg(feature = "ffmpeg")]
+    pub chapters: bool,
+    /// Which players to extract
+    pub players: PlayerFilter,
+    /// Sum every player into a single mono track instead of writing one stream or channel each.
+    /// Not supported for [`Output::Split`], [`Output::WavSplit`] and [`Output::PcmSplit`]
+    pub mix: bool,
+    /// Mixes down into stereo instead of mono, placing players by steam id from -1.0 (left) to
+    /// 1.0 (right). Players without a position are spread evenly across the stereo field, in
+    /// steam id order. Only used with [`ExtractConfig::mix`]
+    pub pan: Option<HashMap<u64, f32>>,
+    /// Linear weight of specific players by steam id in the mix, multiplying what they add to
+    /// every channel of it on top of their gain. Players without one are weighted 1.0. Only used
+    /// with [`ExtractConfig::mix`]
+    pub mix_weights: HashMap<u64, f32>,
+    /// Target integrated loudness in LUFS every player is normalized to. Measuring it takes an
+    /// extra pass that decodes the voice data of every player before anything is encoded
+    pub normalize: Option<f64>,
+    /// Gain in dB applied to every player, on top of any normalization
+    pub gain: f32,
+    /// Gain in dB for specific players by steam id, used instead of [`ExtractConfig::gain`]
+    pub player_gains: HashMap<u64, f32>,
+    /// Seconds every player's voice is shifted by relative to demo time, for syncing it with a
+    /// separate recording: later if positive, earlier if negative. Voice shifted before the start
+    /// of the outputs is dropped, and outputs are lengthened to fit voice shifted past the end.
+    /// Offsets can't be longer than the demo
+    pub offset: f32,
+    /// Offset in seconds for specific players by steam id, used instead of
+    /// [`ExtractConfig::offset`]
+    pub player_offsets: HashMap<u64, f32>,
+    /// Trims silence out of every player's stream. Players no longer line up with each other or
+    /// the demo, so this is only supported when every player gets their own stream
+    pub trim_silence: Option<TrimSilence>,
+    /// Demo time in seconds to start extracting at. Outputs start at this point instead of at
+    /// the start of the demo
+    pub start: Option<f32>,
+    /// Demo time in seconds to stop extracting at
+    pub end: Option<f32>,
+    /// How many players are encoded at once. With a single job every player is encoded while
+    /// the demo is played back; with more, the whole decoded timeline of every player is buffered
+    /// in memory first (about 48KB per second of demo and player for s16, twice that for float)
+    /// and then encoded on up to this many threads
+    pub jobs: NonZeroUsize,
+    /// Print how far into the demo extraction is to stderr, redrawn in place
+    pub progress: bool,
+    /// Seconds of voice a player buffers before it starts playing, so a late packet doesn't
+    /// leave a gap in the middle of speech. Larger values smooth over laggy demos at the cost of
+    /// voice starting later than it was sent; see [`DEFAULT_JITTER_BUFFER_SECONDS`]. Every
+    /// burst of speech starts this late on the output's timeline, and at 0 plays back from the
+    /// frame it arrived in
+    pub jitter_buffer: f32,
+    /// Most lost frames in a row the decoder conceals, at most [`decoder::MAX_PLC_FRAMES`]. See
+    /// [`SteamVoiceDecoder::with_max_plc_frames`] and [`decoder::DEFAULT_MAX_PLC_FRAMES`]
+    pub max_plc_frames: usize,
+    /// Seconds every burst of speech fades in over once it starts playing, 0 to start abruptly
+    pub fade_in: f32,
+    /// Seconds every burst of speech fades out over before the player goes quiet
+    pub fade_out: f32,
+    /// Cutoff in Hz of a high-pass filter run over every player's voice, to take out rumble
+    pub highpass: Option<f32>,
+    /// Writes every player's own stream, and the mix unless it is panned, as two identical
+    /// channels, for software that mishandles mono. Shared WAV and PCM files already give each
+    /// player a channel, so they are left alone
+    pub dual_mono: bool,
+}
+
+impl ExtractConfig {
+    /// Checks the settings that don't depend on the demo, which every kind of extraction does
+    /// before anything is written
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut range = self.start.iter().chain(&self.end);
+        if !range.all(|time| time.is_finite() && *time >= 0.0) {
+            return Err("The time range has to be finite and can't be negative".into());
+        }
+        if let (Some(start), Some(end)) = (self.start, self.end)
+            && end < start
+        {
+            return Err("The end of the time range is before its start".into());
+        }
+        if self.jitter_buffer.is_nan() || self.jitter_buffer < 0.0 {
+            return Err("The jitter buffer can't be negative".into());
+        }
+        let mut offsets = std::iter::once(&self.offset).chain(self.player_offsets.values());
+        if !offsets.all(|offset| offset.is_finite()) {
+            return Err("Offsets have to be finite".into());
+        }
+        if !self
+            .mix_weights
+            .values()
+            .all(|weight| weight.is_finite() && *weight >= 0.0)
+        {
+            return Err("Mix weights have to be finite and can't be negative".into());
+        }
+        Ok(())
+    }
+
+    fn before_start(&self, time: f32) -> bool {
+        self.start.is_some_and(|start| time < start)
+    }
+
+    fn after_end(&self, time: f32) -> bool {
+        self.end.is_some_and(|end| time > end)
+    }
+}
+
+// Only the encoder settings of `config` are used here, which native outputs have no use for
+#[cfg_attr(not(feature = "ffmpeg"), allow(unused_variables))]
+fn create_sink(
+    output: &mut Output,
+    config: &ExtractConfig,
+    mix: Option<&Mixer>,
+    sample_rate: i32,
+    steam_id: u64,
+    name: &str,
+) -> Result<(i32, Sink), Box<dyn std::error::Error>> {
+    if let Some(mix) = mix {
+        // Gains are assigned once every player is known
+        return Ok((mix.decoder_format, Sink::Mix(Vec::new())));
+    }
+    let channels = if config.dual_mono { 2 } else { 1 };
+    Ok(match output {
+        #[cfg(feature = "ffmpeg")]
+        Output::Muxed(fmt_ctx) => {
+            let encoder = Encoder::new(fmt_ctx, config, name, sample_rate, channels)?;
+            (encoder.decoder_format, Sink::Encoder(encoder))
+        }
+        #[cfg(feature = "ffmpeg")]
+        Output::Split(create_output) => {
+            let mut fmt_ctx = create_output(steam_id, name)?;
+            let mut encoder = Encoder::new(&mut fmt_ctx, config, name, sample_rate, channels)?;
+            encoder.fmt_ctx = Some(fmt_ctx);
+            (encoder.decoder_format, Sink::Encoder(encoder))
+        }
+        // Channels are assigned once every player is known
+        Output::Wav(_) => (SAMPLE_FMT_S16, Sink::WavChannel(0)),
+        Output::WavSplit(create_file) => {
+            let file = BufWriter::new(create_file(steam_id, name)?);
+            let writer = WavWriter::new(file, channels as u16, sample_rate as u32)?;
+            (SAMPLE_FMT_S16, Sink::Wav(writer))
+        }
+        Output::Pcm(_, sample_format) => (*sample_format, Sink::WavChannel(0)),
+        Output::PcmSplit(create_file, sample_format) => {
+            let file = BufWriter::new(create_file(steam_id, name)?);
+            let writer = WavWriter::raw(file, channels as u16, bytes_per_sample(*sample_format));
+            (*sample_format, Sink::Wav(writer))
+        }
+        Output::Samples(sample_format) => (*sample_format, Sink::Samples(Vec::new())),
+        Output::Discard => (SAMPLE_FMT_S16, Sink::Discard),
+    })
+}
+
+/// Tells the user how to read a raw PCM output, since the file itself doesn't say
+fn describe_pcm(sample_format: i32, sample_rate: i32, channels: usize) {
+    let format = match sample_format {
+        SAMPLE_FMT_FLT => "f32le",
+        _ => "s16le",
+    };
+    log::info!("Writing raw {format} PCM at {sample_rate}Hz with {channels} channel(s)");
+}
+
+fn discover_players(
+    players: &mut HashMap<u64, PlayerStream>,
+    demo: &Demo,
+    output: &mut Output,
+    config: &ExtractConfig,
+    mix: Option<&Mixer>,
+    sample_rate: i32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let names = resolve_names(demo);
+    // Streams are added in steam id order, so every run gives them the same indices
+    let mut keys: Vec<u64> = scan_players(demo).into_keys().collect();
+    keys.sort();
+    for key in keys {
+        if !config.players.allows(key) {
+            continue;
+        }
+        let name = names.get(&key).cloned().unwrap_or_else(|| key.to_string());
+        let (decoder_format, sink) = create_sink(output, config, mix, sample_rate, key, &name)?;
+        let format = if decoder_format == SAMPLE_FMT_FLT {
+            "f32"
+        } else {
+            "s16"
+        };
+        log::debug!("Created the stream of {name} ({key}), decoding to {format}");
+        players.insert(
+            key,
+            PlayerStream::new(decoder_format, sample_rate, config, name, sink)?,
+        );
+    }
+
+    if matches!(config.players, PlayerFilter::Only(_)) && players.is_empty() {
+        log::warn!("None of the requested players have voice data in this demo");
+        #[cfg(feature = "ffmpeg")]
+        if let (Output::Muxed(fmt_ctx), None) = (output, mix) {
+            // A container needs at least one stream, leave an empty one behind so the output is
+            // still valid
+            Encoder::new(fmt_ctx, config, "empty", sample_rate, 1)?;
+        }
+    }
+    Ok(())
+}
+
+/// Measures the integrated loudness of every player by decoding all of their voice data, and
+/// sets their gain to reach `target` LUFS. Players too quiet to measure are left alone
+fn normalize_players(
+    players: &mut HashMap<u64, PlayerStream>,
+    demo: &Demo,
+    config: &ExtractConfig,
+    sample_rate: i32,
+    target: f64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut meters = HashMap::new();
+    let mut tmp = Vec::new();
+    for (steam_id, player_stream) in players.iter() {
+        let decoder = SteamVoiceDecoder::new(player_stream.sample_format, sample_rate)?
+            .with_max_plc_frames(config.max_plc_frames);
+        meters.insert(*steam_id, (decoder, LoudnessMeter::new(sample_rate)));
+    }
+
+    let frames = playback_frames(demo).filter(|frame| !config.after_end(frame.time));
+    let messages = frames.flat_map(|frame| {
+        let before_start = config.before_start(frame.time);
+        voice_messages(frame).map(move |svc_voice_data| (before_start, svc_voice_data))
+    });
+    for (before_start, svc_voice_data) in messages {
+        // Bad voice data is reported by the main pass
+        let Ok(steam_voice_data) = parse_voice_data(&svc_voice_data.data) else {
+            continue;
+        };
+        let Some((decoder, meter)) = meters.get_mut(&steam_voice_data.steam_id) else {
+            continue;
+        };
+        let player_stream = &players[&steam_voice_data.steam_id];
+        tmp.resize(decoder.output_len(&steam_voice_data), 0);
+        if let Ok(samples_written) = decoder.decode(steam_voice_data, &mut tmp)
+            && !before_start
+        {
+            meter.add_samples(&tmp[..samples_written], player_stream.sample_format);
+        }
+    }
+
+    for (steam_id, (_, meter)) in meters {
+        let Some(loudness) = meter.integrated_loudness() else {
+            continue;
+        };
+        players.get_mut(&steam_id).unwrap().gain = 10f64.powf((target - loudness) / 20.0) as f32;
+    }
+    Ok(())
+}
+
+/// Gains of every mixed down player in each channel of the mix, in the order of `steam_ids`
+fn mix_gains(steam_ids: &[u64], pan: Option<&HashMap<u64, f32>>, channels: usize) -> Vec<Vec<f32>> {
+    let Some(pan) = pan else {
+        return vec![vec![1.0; channels]; steam_ids.len()];
+    };
+    let unplaced: Vec<u64> = steam_ids
+        .iter()
+        .copied()
+        .filter(|steam_id| !pan.contains_key(steam_id))
+        .collect();
+    steam_ids
+        .iter()
+        .map(|steam_id| {
+            let position = pan.get(steam_id).copied().unwrap_or_else(|| {
+                let slot = unplaced.iter().position(|id| id == steam_id).unwrap();
+                if unplaced.len() == 1 {
+                    0.0
+                } else {
+                    slot as f32 / (unplaced.len() - 1) as f32 * 2.0 - 1.0
+                }
+            });
+            // Constant power panning, so players sound equally loud wherever they are placed
+            let angle = (position.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+            vec![angle.cos(), angle.sin()]
+        })
+        .collect()
+}
+
+/// Encodes the buffered timeline of every player on up to `jobs` threads. Players without their
+/// own container are left with their packets in [`Encoder::queued_packets`].
+#[cfg(feature = "ffmpeg")]
+fn encode_parallel(
+    players: &mut HashMap<u64, PlayerStream>,
+    jobs: NonZeroUsize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut batches: Vec<Vec<(&mut Encoder, Vec<u8>)>> = Vec::new();
+    batches.resize_with(jobs.get(), Vec::new);
+    let encoders = players.values_mut().filter_map(|player_stream| {
+        let Sink::Encoder(encoder) = &mut player_stream.sink else {
+            return None;
+        };
+        Some((encoder, std::mem::take(&mut player_stream.timeline)))
+    });
+    for (i, job) in encoders.enumerate() {
+        batches[i % jobs.get()].push(job);
+    }
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = batches
+            .into_iter()
+            .filter(|batch| !batch.is_empty())
+            .map(|batch| {
+                scope.spawn(move || -> Result<(), String> {
+                    for (encoder, samples) in batch {
+                        encoder.encode(&samples, None).map_err(|e| e.to_string())?;
+                        encoder.finish(None).map_err(|e| e.to_string())?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().expect("Encoder thread panicked")?;
+        }
+        Ok(())
+    })
+}
+
+/// Writes the packets queued up by [`encode_parallel`] into the shared container, interleaved by
+/// decoding timestamp
+#[cfg(feature = "ffmpeg")]
+fn mux_queued_packets(
+    players: &mut HashMap<u64, PlayerStream>,
+    fmt_ctx: &mut AVFormatContextOutput,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut packets = Vec::new();
+    for player_stream in players.values_mut() {
+        let Sink::Encoder(encoder) = &mut player_stream.sink else {
+            continue;
+        };
+        let time_base = fmt_ctx.streams()[encoder.stream_index].time_base;
+        for mut pkt in encoder.queued_packets.drain(..) {
+            pkt.rescale_ts(encoder.codec_ctx.time_base, time_base);
+            let seconds = pkt.dts as f64 * time_base.num as f64 / time_base.den as f64;
+            packets.push((seconds, pkt));
+        }
+    }
+    packets.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    for (_, mut pkt) in packets {
+        fmt_ctx.write_frame(&mut pkt)?;
+    }
+    Ok(())
+}
+
+/// Returned by [`extract`] when nobody in the demo ever talks, so there is nothing to extract
+#[derive(Debug, thiserror::Error)]
+#[error("No voice data found in the demo")]
+pub struct NoVoiceData;
+
+/// Returned by [`extract`] for demos recorded before Steam voice, whose voice is in a codec that
+/// can't be decoded
+#[derive(Debug, thiserror::Error)]
+#[error("The demo's voice data is in the legacy {codec} codec, only Steam voice is supported")]
+pub struct UnsupportedVoiceCodec {
+    /// Codec named by `svc_voiceinit`, such as `voice_speex` or `voice_miles`
+    pub codec: String,
+}
+
+/// Fails with [`NoVoiceData`] if nobody in the demo talks, or [`UnsupportedVoiceCodec`] if they
+/// do but none of it is Steam voice
+pub fn check_voice_data(demo: &Demo) -> Result<(), Box<dyn std::error::Error>> {
+    if !scan_players(demo).is_empty() {
+        return Ok(());
+    }
+    if playback_frames(demo)
+        .flat_map(voice_messages)
+        .next()
+        .is_none()
+    {
+        return Err(NoVoiceData.into());
+    }
+    let codec = voice_format(demo)
+        .codec_name
+        .unwrap_or_else(|| "unknown".to_string());
+    Err(UnsupportedVoiceCodec { codec }.into())
+}
+
+/// What was extracted from a demo, see [`extract`]
+#[derive(Debug, Clone, Default)]
+pub struct ExtractSummary {
+    /// Rate every output was written at
+    pub sample_rate: i32,
+    /// Every extracted player, by steam id
+    pub players: HashMap<u64, PlayerSummary>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayerSummary {
+    /// Display name, the steam id if the demo doesn't record one
+    pub name: String,
+    /// When the player was talking, in seconds from the start of the outputs. These follow the
+    /// demo and don't account for trimmed silence
+    pub speech: Vec<Range<f64>>,
+    /// How many samples of voice were decoded
+    pub decoded_samples: usize,
+    /// Largest absolute sample of the decoded voice, as a fraction of full scale, before any gain.
+    /// 0 for raw opus, which isn't decoded
+    pub peak: f32,
+    /// RMS level of everything decoded for the player (not the silence padding their stream
+    /// between messages), as a fraction of full scale, before any gain. 0 for raw opus
+    pub rms: f32,
+    /// What the player's decoder went through
+    pub decoder: DecoderStats,
+    /// Everything decoded for the player with [`Output::Samples`], empty for any other output
+    pub samples: Vec<u8>,
+}
+
+/// Decodes the voice data of every player in `demo` and encodes it into `output`, one stream
+/// per player.
+///
+/// A muxed output context must not have had its header written yet; this writes both the header
+/// and the trailer of every container involved.
+pub fn extract(
+    demo: &Demo,
+    config: &ExtractConfig,
+    output: Output,
+) -> Result<ExtractSummary, Box<dyn std::error::Error>> {
+    extract_with_callback(demo, config, output, |_, _, _| {})
+}
+
+/// Like [`extract`], calling `on_voice` with the steam id, demo time and number of decoded
+/// samples of every voice message as it is decoded, such as to draw a live waveform. Only voice
+/// within the time range of [`ExtractConfig::start`] and [`ExtractConfig::end`] is reported.
+pub fn extract_with_callback(
+    demo: &Demo,
+    config: &ExtractConfig,
+    output: Output,
+    on_voice: impl FnMut(u64, f32, usize),
+) -> Result<ExtractSummary, Box<dyn std::error::Error>> {
+    extract_demo(DemoInput::Borrowed(demo), config, output, on_voice)
+}
+
+/// Like [`extract`], but takes over the demo and frees every frame of it as soon as it has been
+/// played back, rather than keeping the whole demo around until extraction is done. See the
+/// [crate docs](crate#memory) for what that saves.
+pub fn extract_owned(
+    demo: Demo,
+    config: &ExtractConfig,
+    output: Output,
+) -> Result<ExtractSummary, Box<dyn std::error::Error>> {
+    extract_demo(DemoInput::Owned(demo), config, output, |_, _, _| {})
+}
+
+/// A demo to extract, one that is handed over can be dropped while it is played back
+enum DemoInput<'a> {
+    Borrowed(&'a Demo),
+    Owned(Demo),
+}
+
+fn extract_demo(
+    input: DemoInput,
+    config: &ExtractConfig,
+    mut output: Output,
+    mut on_voice: impl FnMut(u64, f32, usize),
+) -> Result<ExtractSummary, Box<dyn std::error::Error>> {
+    let demo = match &input {
+        DemoInput::Borrowed(demo) => demo,
+        DemoInput::Owned(demo) => demo,
+    };
+    config.validate()?;
+    check_voice_data(demo)?;
+
+    let announced_rate = voice_format(demo).sample_rate;
+    if announced_rate != SAMPLE_RATE {
+        log::warn!("Voice data is encoded at {announced_rate}Hz instead of {SAMPLE_RATE}Hz");
+    }
+    let sample_rate = decoder::decoding_rate(announced_rate);
+    log::debug!("Decoding voice data announced at {announced_rate}Hz at {sample_rate}Hz");
+    if let Some(cutoff) = config.highpass
+        && !(cutoff > 0.0 && cutoff < sample_rate as f32 / 2.0)
+    {
+        return Err(format!(
+            "The high-pass cutoff has to be between 0 and {}Hz",
+            sample_rate / 2
+        )
+        .into());
+    }
+
+    let mix_channels = if config.pan.is_some() || config.dual_mono {
+        2
+    } else {
+        1
+    };
+    let mut mix = match &mut output {
+        #[cfg(feature = "ffmpeg")]
+        Output::Muxed(fmt_ctx) if config.mix => {
+            let encoder = Encoder::new(fmt_ctx, config, "mix", sample_rate, mix_channels)?;
+            Some(Mixer {
+                decoder_format: encoder.decoder_format,
+                samples: Vec::new(),
+                sink: Sink::Encoder(encoder),
+            })
+        }
+        #[cfg(feature = "ffmpeg")]
+        Output::Split(_) if config.mix => {
+            return Err("Split outputs can't be mixed down".into());
+        }
+        Output::WavSplit(_) | Output::PcmSplit(..) if config.mix => {
+            return Err("Split outputs can't be mixed down".into());
+        }
+        Output::Samples(_) if config.mix => {
+            return Err("Samples kept in memory can't be mixed down".into());
+        }
+        _ => None,
+    };
+    if let Output::PcmSplit(_, sample_format) = output {
+        describe_pcm(
+            sample_format,
+            sample_rate,
+            if config.dual_mono { 2 } else { 1 },
+        );
+    }
+
+    let mut players: HashMap<u64, PlayerStream> = HashMap::new();
+    discover_players(
+        &mut players,
+        demo,
+        &mut output,
+        config,
+        mix.as_ref(),
+        sample_rate,
+    )?;
+
+    if let Some(target) = config.normalize {
+        normalize_players(&mut players, demo, config, sample_rate, target)?;
+    }
+    for (steam_id, player_stream) in players.iter_mut() {
+        let gain_db = config.player_gains.get(steam_id).unwrap_or(&config.gain);
+        player_stream.gain *= 10f32.powf(gain_db / 20.0);
+    }
+    // Shifting voice further than the demo is long only ever leaves silence, and would buffer
+    // that much of it
+    let demo_length = playback_frames(demo).last().map_or(0.0, |frame| frame.time);
+    let mut offsets: HashMap<u64, i64> = HashMap::new();
+    for steam_id in players.keys() {
+        let offset = config
+            .player_offsets
+            .get(steam_id)
+            .unwrap_or(&config.offset);
+        if offset.abs() > demo_length {
+            return Err(format!(
+                "An offset of {offset}s is longer than the {demo_length:.3}s demo"
+            )
+            .into());
+        }
+        offsets.insert(*steam_id, (offset * sample_rate as f32).round() as i64);
+    }
+    // Voice can't be played back before demo time gets to it, so every player is delayed by as
+    // much as the furthest advanced one, and the start of every output is skipped to make up
+    let lead = offsets.values().copied().min().unwrap_or(0).min(0);
+    let lag = offsets.values().copied().max().unwrap_or(0).max(0);
+    for (steam_id, player_stream) in players.iter_mut() {
+        let offset = offsets[steam_id];
+        let delay = offset
+            .checked_sub(lead)
+            .and_then(|delay| usize::try_from(delay).ok())
+            .and_then(|delay| delay.checked_mul(player_stream.bytes_per_sample))
+            .ok_or("Offsets are too far apart")?;
+        player_stream.offset = offset;
+        player_stream.skip = lead.unsigned_abs() as usize;
+        player_stream.delay.resize(delay, 0);
+    }
+
+    let mut steam_ids: Vec<u64> = players.keys().copied().collect();
+    steam_ids.sort();
+    #[cfg(feature = "ffmpeg")]
+    let mut muxer = None;
+    let mut wav = None;
+    // The shared native file, and the sample format if it is raw PCM instead of WAV
+    let mut shared_file = None;
+    match output {
+        #[cfg(feature = "ffmpeg")]
+        Output::Muxed(fmt_ctx) => muxer = Some(fmt_ctx),
+        Output::Wav(file) => shared_file = Some((file, None)),
+        Output::Pcm(file, sample_format) => shared_file = Some((file, Some(sample_format))),
+        #[cfg(feature = "ffmpeg")]
+        Output::Split(_) => {}
+        Output::WavSplit(_) | Output::PcmSplit(..) | Output::Samples(_) | Output::Discard => {}
+    };
+    if let Some((file, raw_format)) = shared_file {
+        let channels = if config.mix {
+            mix_channels
+        } else {
+            steam_ids.len().max(1)
+        };
+        let file = BufWriter::new(file);
+        let (sample_format, writer) = match raw_format {
+            Some(sample_format) => {
+                describe_pcm(sample_format, sample_rate, channels);
+                let bytes_per_sample = bytes_per_sample(sample_format);
+                let writer = WavWriter::raw(file, channels as u16, bytes_per_sample);
+                (sample_format, writer)
+            }
+            None => {
+                let writer = WavWriter::new(file, channels as u16, sample_rate as u32)?;
+                (SAMPLE_FMT_S16, writer)
+            }
+        };
+        if config.mix {
+            mix = Some(Mixer {
+                decoder_format: sample_format,
+                samples: Vec::new(),
+                sink: Sink::Wav(writer),
+            });
+        } else {
+            for (channel, steam_id) in steam_ids.iter().enumerate() {
+                players.get_mut(steam_id).unwrap().sink = Sink::WavChannel(channel);
+            }
+            wav = Some((writer, vec![Vec::new(); channels]));
+        }
+    }
+    if config.mix {
+        let gains = mix_gains(&steam_ids, config.pan.as_ref(), mix_channels);
+        for (steam_id, mut gains) in steam_ids.iter().zip(gains) {
+            let weight = config.mix_weights.get(steam_id).copied().unwrap_or(1.0);
+            gains.iter_mut().for_each(|gain| *gain *= weight);
+            players.get_mut(steam_id).unwrap().sink = Sink::Mix(gains);
+        }
+    }
+    if let Some(trim) = &config.trim_silence {
+        if mix.is_some() || wav.is_some() {
+            return Err(
+                "Silence can only be trimmed when every player gets their own stream".into(),
+            );
+        }
+        for player_stream in players.values_mut() {
+            let trimmer = SilenceTrimmer::new(trim, sample_rate, player_stream.sample_format);
+            player_stream.trimmer = Some(trimmer);
+        }
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    {
+        if let Some(fmt_ctx) = muxer.as_deref_mut() {
+            fmt_ctx.write_header(&mut None)?;
+        }
+        for player_stream in players.values_mut() {
+            if let Sink::Encoder(Encoder {
+                fmt_ctx: Some(fmt_ctx),
+                ..
+            }) = &mut player_stream.sink
+            {
+                fmt_ctx.write_header(&mut None)?;
+            }
+        }
+    }
+    let mut last_frame_time: Option<f32> = None;
+    let mut rewound = false;
+    let mut unparsed = 0;
+    // Decoded voice, reused for every message
+    let mut tmp = Vec::new();
+    #[cfg(feature = "ffmpeg")]
+    let parallel = config.jobs.get() > 1;
+    // Hands every player's samples for the same stretch of the output to their sink, then writes
+    // the mix and the shared file
+    let mut write_output = |players: &mut HashMap<u64, PlayerStream>,
+                            produce: &mut dyn FnMut(&mut PlayerStream) -> Vec<u8>|
+     -> Result<(), Box<dyn std::error::Error>> {
+        for player_stream in players.values_mut() {
+            let samples = produce(player_stream);
+            match &mut player_stream.sink {
+                #[cfg(feature = "ffmpeg")]
+                Sink::Encoder(_) if parallel => player_stream.timeline.extend_from_slice(&samples),
+                Sink::WavChannel(channel) => {
+                    let (_, channels) = wav.as_mut().expect("WAV channel without a shared file");
+                    channels[*channel] = samples;
+                }
+                Sink::Mix(gains) => {
+                    let mix = mix.as_mut().expect("Mixed player without a mix");
+                    mix_samples(&mut mix.samples, &samples, mix.decoder_format, gains);
+                }
+                sink => sink.write(
+                    &samples,
+                    #[cfg(feature = "ffmpeg")]
+                    muxer.as_deref_mut(),
+                )?,
+            }
+        }
+        if let Some(mix) = mix.as_mut() {
+            mix.sink.write(
+                &mix.samples,
+                #[cfg(feature = "ffmpeg")]
+                muxer.as_deref_mut(),
+            )?;
+            mix.samples.clear();
+        }
+        if let Some((writer, channels)) = wav.as_mut() {
+            writer.write_channels(channels)?;
+        }
+        Ok(())
+    };
+
+    // Outputs start at the start of the time range rather than at the start of the demo
+    let start = config.start.unwrap_or(0.0);
+    let mut progress = Progress::new(config.progress, start, {
+        let last_frame = playback_frames(demo)
+            .last()
+            .map_or(start, |frame| frame.time);
+        config.end.map_or(last_frame, |end| end.min(last_frame))
+    });
+    // Every frame of a demo that was handed over is dropped as soon as it has been played back
+    let frames: Box<dyn Iterator<Item = Cow<Frame>>> = match input {
+        DemoInput::Borrowed(demo) => Box::new(playback_frames(demo).map(Cow::Borrowed)),
+        DemoInput::Owned(demo) => Box::new(into_playback_frames(demo).map(Cow::Owned)),
+    };
+    for demo_frame in frames.filter(|frame| !config.after_end(frame.time)) {
+        let demo_frame: &Frame = &demo_frame;
+        // Voice from before the start is still decoded so the decoder keeps up with the sequence
+        // numbers, but nothing of it is played back
+        let before_start = config.before_start(demo_frame.time);
+        for svc_voice_data in voice_messages(demo_frame) {
+            let Ok(steam_voice_data) = parse_voice_data(&svc_voice_data.data) else {
+                // Only the first one is reported, demos with one tend to be full of them
+                unparsed += 1;
+                if unparsed == 1 {
+                    progress.interrupt();
+                    log::warn!("Failed to parse svc_voice_data as steam voice data!");
+                }
+                continue;
+            };
+
+            let key = steam_voice_data.steam_id;
+
+            let Some(player_stream) = players.get_mut(&key) else {
+                // Filtered out
+                continue;
+            };
+
+            tmp.resize(player_stream.decoder.output_len(&steam_voice_data), 0);
+            match player_stream.decoder.decode(steam_voice_data, &mut tmp) {
+                Ok(_) if before_start => {}
+                Ok(samples_written) => {
+                    player_stream.append_samples(&tmp[..samples_written]);
+                    let sample_count = samples_written / player_stream.bytes_per_sample;
+                    on_voice(key, demo_frame.time, sample_count);
+                }
+                Err(e) => {
+                    progress.interrupt();
+                    log::warn!("Decoder error: {e}");
+                }
+            }
+        }
+
+        if before_start {
+            continue;
+        }
+
+        let frametime = if let Some(prev) = last_frame_time {
+            demo_frame.time - prev
+        } else {
+            0.0
+        };
+        // Seeks and HLTV demos can jump back in time, which the outputs can't. Playback holds at
+        // the latest time until the demo catches up with it again, while voice is still decoded
+        if frametime < 0.0 {
+            if !rewound {
+                progress.interrupt();
+                log::warn!(
+                    "Demo time jumps back from {:.3}s to {:.3}s, holding playback until it \
+                     catches up",
+                    last_frame_time.unwrap_or_default(),
+                    demo_frame.time
+                );
+                rewound = true;
+            }
+            continue;
+        }
+        rewound = false;
+        last_frame_time = Some(demo_frame.time);
+
+        // Although we're looping through parsed "frames", they're really just sections of
+        // information about a demo at a given time. These sections will group together on game
+        // frames, but there can be mutliple parsed demo "frames" in a game frame with
+        // different kinds of information about the game state. We use the frame rate
+        // to replicate the engine behavior with audio buffering, but we don't actually care
+        // about each frame
+        if frametime == 0.0 {
+            continue;
+        }
+        progress.update(demo_frame.time);
+
+        let demo_frame_time_as_pts =
+            ((demo_frame.time - start) * sample_rate as f32).floor() as i64;
+
+        write_output(&mut players, &mut |player_stream| {
+            player_stream.advance(frametime, demo_frame_time_as_pts)
+        })?;
+    }
+
+    // Whatever is still delayed plays after the end of the demo, along with what the skipped
+    // start of the outputs shortened them by
+    let skipped = players.values().next().map_or(0, |player_stream| {
+        lead.unsigned_abs() as usize - player_stream.skip
+    });
+    let tail = lag as usize + skipped;
+    if tail > 0 {
+        write_output(&mut players, &mut |player_stream| {
+            player_stream.finish_shift(tail)
+        })?;
+    }
+
+    progress.finish();
+    if unparsed > 1 {
+        log::warn!("Skipped {unparsed} svc_voice_data messages that weren't steam voice data");
+    }
+
+    // Silence after the last bit of speech is only known to be trailing once playback is done
+    if config.trim_silence.is_some() {
+        write_output(&mut players, &mut |player_stream| {
+            let samples = player_stream
+                .trimmer
+                .as_mut()
+                .map(SilenceTrimmer::finish)
+                .unwrap_or_default();
+            player_stream.to_channels(samples)
+        })?;
+    }
+
+    // Chapters are written along with the trailer, which a player's own container gets as soon
+    // as their encoder is finished
+    #[cfg(feature = "ffmpeg")]
+    if config.chapters {
+        for player_stream in players.values_mut() {
+            if let Sink::Encoder(Encoder {
+                fmt_ctx: Some(fmt_ctx),
+                ..
+            }) = &mut player_stream.sink
+            {
+                let name = player_stream.name.as_str();
+                let speech = player_stream.speech.iter().map(|range| (range, name));
+                encoder::add_chapters(fmt_ctx, speech, sample_rate)?;
+            }
+        }
+        if let Some(fmt_ctx) = muxer.as_deref_mut() {
+            let mut speech: Vec<_> = players
+                .values()
+                .flat_map(|player| {
+                    let name = player.name.as_str();
+                    player.speech.iter().map(move |range| (range, name))
+                })
+                .collect();
+            speech.sort_by_key(|&(range, name)| (range.start, name));
+            encoder::add_chapters(fmt_ctx, speech, sample_rate)?;
+        }
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    if parallel {
+        encode_parallel(&mut players, config.jobs)?;
+    }
+
+    // Flush
+    for player_stream in players.values_mut() {
+        // Already flushed by its encoding thread
+        #[cfg(feature = "ffmpeg")]
+        if parallel && matches!(player_stream.sink, Sink::Encoder(_)) {
+            continue;
+        }
+        player_stream.sink.finish(
+            #[cfg(feature = "ffmpeg")]
+            muxer.as_deref_mut(),
+        )?;
+    }
+    if let Some(mix) = mix.as_mut() {
+        mix.sink.finish(
+            #[cfg(feature = "ffmpeg")]
+            muxer.as_deref_mut(),
+        )?;
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    if let Some(fmt_ctx) = muxer {
+        mux_queued_packets(&mut players, fmt_ctx)?;
+        fmt_ctx.write_trailer()?;
+    }
+    if let Some((mut writer, _)) = wav {
+        writer.finish()?;
+    }
+
+    for (steam_id, player_stream) in &players {
+        let stats = player_stream.decoder.stats();
+        log::debug!(
+            "{} ({steam_id}): decoded {} samples ({} bytes) from {} opus packets, {} lost",
+            player_stream.name,
+            player_stream.decoded_sample_count,
+            player_stream.decoded_sample_count * player_stream.bytes_per_sample,
+            stats.packets,
+            stats.lost_packets,
+        );
+    }
+
+    let pts_to_seconds = |pts: i64| pts as f64 / sample_rate as f64;
+    let players = players
+        .into_iter()
+        .map(|(steam_id, player_stream)| {
+            let speech = player_stream
+                .speech
+                .iter()
+                .map(|interval| pts_to_seconds(interval.start)..pts_to_seconds(interval.end))
+                .collect();
+            let summary = PlayerSummary {
+                speech,
+                decoded_samples: player_stream.decoded_sample_count,
+                peak: player_stream.peak,
+                rms: if player_stream.decoded_sample_count == 0 {
+                    0.0
+                } else {
+                    (player_stream.sum_of_squares / player_stream.decoded_sample_count as f64)
+                        .sqrt() as f32
+                },
+                decoder: player_stream.decoder.stats(),
+                samples: match player_stream.sink {
+                    Sink::Samples(samples) => samples,
+                    _ => Vec::new(),
+                },
+                name: player_stream.name,
+            };
+            (steam_id, summary)
+        })
+        .collect();
+    Ok(ExtractSummary {
+        sample_rate,
+        players,
+    })
+}
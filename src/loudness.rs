@@ -0,0 +1,126 @@
+use std::f64::consts::PI;
+
+use crate::dsp::Biquad;
+use crate::{SAMPLE_FMT_FLT, SAMPLE_FMT_S16};
+
+/// Blocks quieter than this never count towards the integrated loudness
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+/// Blocks this far below the loudness of the non silent blocks are dropped as well
+const RELATIVE_GATE_LU: f64 = -10.0;
+/// Loudness is measured over 400ms blocks overlapping by 75%, so every block spans four of these
+const SUB_BLOCKS_PER_BLOCK: usize = 4;
+
+/// Measures the integrated loudness of a mono signal as described by ITU-R BS.1770 / EBU R128.
+///
+/// Only the mean square of every 100ms is kept around, so a meter can be fed a whole demo without
+/// buffering its samples.
+pub struct LoudnessMeter {
+    shelf: Biquad,
+    high_pass: Biquad,
+    sub_block_len: usize,
+    sum: f64,
+    count: usize,
+    sub_blocks: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: i32) -> Self {
+        let rate = sample_rate as f64;
+
+        // K-weighting filter coefficients, recalculated for the given sample rate
+        let k = (PI * 1681.974450955533 / rate).tan();
+        let q = 0.7071752369554196;
+        let vh = 10f64.powf(3.999843853973347 / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        };
+
+        let k = (PI * 38.13547087602444 / rate).tan();
+        let q = 0.5003270373238773;
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        };
+
+        Self {
+            shelf,
+            high_pass,
+            sub_block_len: (sample_rate / 10) as usize,
+            sum: 0.0,
+            count: 0,
+            sub_blocks: Vec::new(),
+        }
+    }
+
+    /// Feeds samples in `sample_format` (s16 or float) into the meter
+    pub fn add_samples(&mut self, samples: &[u8], sample_format: i32) {
+        match sample_format {
+            SAMPLE_FMT_S16 => {
+                for sample in samples.chunks_exact(2) {
+                    let sample = i16::from_ne_bytes([sample[0], sample[1]]);
+                    self.add_sample(sample as f64 / 32768.0);
+                }
+            }
+            SAMPLE_FMT_FLT => {
+                for sample in samples.chunks_exact(4) {
+                    self.add_sample(f32::from_ne_bytes(sample.try_into().unwrap()) as f64);
+                }
+            }
+            _ => panic!("measuring samples in a sample format that we didn't account for!"),
+        }
+    }
+
+    fn add_sample(&mut self, sample: f64) {
+        let weighted = self.high_pass.process(self.shelf.process(sample));
+        self.sum += weighted * weighted;
+        self.count += 1;
+        if self.count == self.sub_block_len {
+            self.sub_blocks.push(self.sum / self.count as f64);
+            self.sum = 0.0;
+            self.count = 0;
+        }
+    }
+
+    /// Integrated loudness in LUFS of everything fed in so far, or `None` if it was too short or
+    /// too quiet to measure
+    pub fn integrated_loudness(&self) -> Option<f64> {
+        let blocks: Vec<f64> = self
+            .sub_blocks
+            .windows(SUB_BLOCKS_PER_BLOCK)
+            .map(|window| window.iter().sum::<f64>() / SUB_BLOCKS_PER_BLOCK as f64)
+            .filter(|&mean_square| loudness(mean_square) > ABSOLUTE_GATE_LUFS)
+            .collect();
+        if blocks.is_empty() {
+            return None;
+        }
+        let relative_gate = loudness(mean(&blocks)) + RELATIVE_GATE_LU;
+        let gated: Vec<f64> = blocks
+            .into_iter()
+            .filter(|&mean_square| loudness(mean_square) > relative_gate)
+            .collect();
+        if gated.is_empty() {
+            return None;
+        }
+        Some(loudness(mean(&gated)))
+    }
+}
+
+fn loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
@@ -1,415 +1,1507 @@
-use clap::Parser;
-use dem::open_demo;
-use dem::types::{Demo, EngineMessage, FrameData, MessageData, NetMessage};
+use clap::{ArgAction, CommandFactory, Parser, ValueEnum, error::ErrorKind};
+use dem::{open_demo, types::Demo};
+use goldsrc_voice_extract::{
+    DEFAULT_JITTER_BUFFER_SECONDS, ExtractConfig, ExtractSummary, NoVoiceData, Output,
+    PlayerFilter, SAMPLE_FMT_FLT, SAMPLE_FMT_S16, TrimSilence, UnsupportedVoiceCodec, check_voice,
+    check_voice_data, concat_demos,
+    decoder::{DEFAULT_MAX_PLC_FRAMES, MAX_PLC_FRAMES},
+    extract_owned, extract_raw_opus,
+    json::Json,
+    read_demo, resolve_names, scan_players, speech_cues, write_cue, write_speech_csv, write_srt,
+    write_vtt,
+};
+#[cfg(feature = "ffmpeg")]
 use rsmpeg::{
-    avcodec::{AVCodec, AVCodecContext, AVCodecRef},
-    avformat::AVFormatContextOutput,
-    avutil::{AVChannelLayout, AVFrame, get_bytes_per_sample},
-    ffi::{AV_CODEC_CONFIG_SAMPLE_RATE, AV_SAMPLE_FMT_FLT, AV_SAMPLE_FMT_S16, AVRational},
-    swresample::SwrContext,
+    avcodec::AVCodec,
+    avformat::{
+        AVFormatContextOutput, AVIOContextContainer, AVIOContextCustom, AVOutputFormat,
+        WritePacketCallback,
+    },
+    avutil::AVMem,
+    ffi,
 };
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "ffmpeg")]
 use std::ffi::CString;
-use steam_audio_codec::SteamVoiceData;
-
-mod decoder;
-use decoder::SteamVoiceDecoder;
-
-const INITIAL_TIME_PAD_SECONDS: f32 = 0.2;
-pub const SAMPLE_RATE: i32 = 24_000;
-
-struct PlayerStream {
-    decoded_samples: VecDeque<u8>,
-    time_pad: f32,
-    playing: bool,
-    frame_accum: Vec<u8>,
-    codec_ctx: AVCodecContext,
-    stream_index: usize,
-    frame: AVFrame,
-    pts: i64,
-    last_demo_pts: i64,
-    decoder: SteamVoiceDecoder,
-    bytes_per_sample: usize,
-    enc_bytes_per_sample: usize,
-    resampler: Option<SwrContext>,
-}
-
-impl PlayerStream {
-    fn new(
-        fmt_ctx: &mut AVFormatContextOutput,
-        codec: &AVCodecRef<'static>,
-        bitrate: Option<i64>,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut codec_ctx = AVCodecContext::new(codec);
-
-        let channel_layout = AVChannelLayout::from_nb_channels(1).into_inner();
-        let supported_fmts = codec_ctx.get_supported_sample_fmts(None)?;
-
-        let (decoder_format, encoder_format) = if supported_fmts.contains(&AV_SAMPLE_FMT_S16) {
-            (AV_SAMPLE_FMT_S16, AV_SAMPLE_FMT_S16)
-        } else if supported_fmts.contains(&AV_SAMPLE_FMT_FLT) {
-            (AV_SAMPLE_FMT_FLT, AV_SAMPLE_FMT_FLT)
-        } else {
-            let encoder_format = supported_fmts
-                .first()
-                .copied()
-                .ok_or("Encoder does not report supported sample formats?")?;
-            (AV_SAMPLE_FMT_S16, encoder_format)
-        };
+use std::fs::File;
+#[cfg(feature = "ffmpeg")]
+use std::io::Write;
+use std::io::{BufWriter, IsTerminal};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-        let supported_rates =
-            unsafe { codec_ctx.get_supported_config::<i32>(None, AV_CODEC_CONFIG_SAMPLE_RATE) }?;
-        let encoder_rate = if supported_rates.is_empty() || supported_rates.contains(&SAMPLE_RATE) {
-            SAMPLE_RATE
-        } else {
-            supported_rates
-                .first()
-                .copied()
-                .expect("Coudln't get first supported rate?")
-        };
-
-        let resampler = if decoder_format != encoder_format || SAMPLE_RATE != encoder_rate {
-            let mut swr = SwrContext::new(
-                &channel_layout,
-                encoder_format,
-                encoder_rate,
-                &channel_layout,
-                decoder_format,
-                SAMPLE_RATE,
-            )?;
-            swr.init()?;
-            Some(swr)
-        } else {
-            None
-        };
+/// Output path that streams the muxed output to stdout instead
+const STDOUT_OUTPUT: &str = "-";
+/// Input path that reads the demo from stdin instead
+const STDIN_INPUT: &str = "-";
 
-        codec_ctx.set_sample_fmt(encoder_format);
-        codec_ctx.set_ch_layout(channel_layout);
-        codec_ctx.set_sample_rate(encoder_rate);
-        codec_ctx.set_time_base(AVRational {
-            num: 1,
-            den: codec_ctx.sample_rate,
-        });
-        if let Some(bitrate) = bitrate {
-            codec_ctx.set_bit_rate(bitrate);
-        }
+/// Formats that seek back into their output to finish it, which stdout can't do
+#[cfg(feature = "ffmpeg")]
+const SEEKING_FORMATS: [&str; 8] = ["mov", "mp4", "ipod", "psp", "3gp", "3g2", "ismv", "f4v"];
 
-        codec_ctx.open(None)?;
+/// Formats that carry chapters added after their header was written
+#[cfg(feature = "ffmpeg")]
+const CHAPTER_FORMATS: [&str; 5] = ["matroska", "webm", "mov", "mp4", "ipod"];
 
-        let stream_index = {
-            let mut stream = fmt_ctx.new_stream();
-            stream.set_codecpar(codec_ctx.extract_codecpar());
-            stream.set_time_base(codec_ctx.time_base);
-            stream.index as usize
-        };
+/// Channel layout given to --channels
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Channels {
+    Mono,
+    Stereo,
+}
 
-        let mut frame = AVFrame::new();
-        let frame_size = if codec_ctx.frame_size > 0 {
-            codec_ctx.frame_size
-        } else {
-            1024
-        };
-        frame.set_nb_samples(frame_size);
-        frame.set_format(codec_ctx.sample_fmt);
-        frame.set_ch_layout(codec_ctx.ch_layout);
-        frame.set_sample_rate(codec_ctx.sample_rate);
-        frame.get_buffer(0)?;
-
-        Ok(Self {
-            decoded_samples: VecDeque::new(),
-            time_pad: INITIAL_TIME_PAD_SECONDS,
-            playing: false,
-            frame_accum: Vec::with_capacity(frame_size as usize),
-            codec_ctx,
-            stream_index,
-            frame,
-            pts: 0,
-            last_demo_pts: 0,
-            decoder: SteamVoiceDecoder::new(decoder_format)?,
-            bytes_per_sample: get_bytes_per_sample(decoder_format)
-                .expect("Couldn't get bytes per sample of sample format???"),
-            enc_bytes_per_sample: get_bytes_per_sample(encoder_format)
-                .expect("Coudln't get bytes per sample on encoder format?"),
-            resampler,
-        })
-    }
+/// Sample format of a --raw-pcm output
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PcmFormat {
+    S16,
+    F32,
+}
 
-    fn append_samples(&mut self, samples: impl IntoIterator<Item = u8>) {
-        if self.buffered_samples() == 0 {
-            self.time_pad = INITIAL_TIME_PAD_SECONDS;
+impl PcmFormat {
+    fn sample_format(self) -> i32 {
+        match self {
+            PcmFormat::S16 => SAMPLE_FMT_S16,
+            PcmFormat::F32 => SAMPLE_FMT_FLT,
         }
-        self.decoded_samples.extend(samples);
     }
 
-    fn consume_samples(&mut self, sample_count: usize) -> impl Iterator<Item = u8> {
-        let bytes = sample_count * self.bytes_per_sample;
-        let samples = self
-            .decoded_samples
-            .drain(..bytes.min(self.decoded_samples.len()));
-        let padded_with_zeroes = samples.chain(core::iter::repeat(0));
-        padded_with_zeroes.take(bytes)
-    }
-
-    fn buffered_samples(&self) -> usize {
-        self.decoded_samples.len()
+    /// What ffmpeg calls the codec of these samples
+    fn codec_name(self) -> &'static str {
+        match self {
+            PcmFormat::S16 => "pcm_s16le",
+            PcmFormat::F32 => "pcm_f32le",
+        }
     }
-}
 
-fn discover_players(
-    players: &mut HashMap<u64, PlayerStream>,
-    demo: &Demo,
-    fmt_ctx: &mut AVFormatContextOutput,
-    codec: &AVCodecRef<'static>,
-    bitrate: Option<i64>,
-) {
-    for entry in &demo.directory.entries {
-        if entry.type_ == 0 {
-            continue;
-        } // nEntryType == DEMO_STARTUP
-        for frame in &entry.frames {
-            let FrameData::NetworkMessage(ref boxed_network_message) = frame.frame_data else {
-                continue;
-            };
-            let network_message = &boxed_network_message.1;
-            let MessageData::Parsed(ref messages) = network_message.messages else {
-                continue;
-            };
-            for message in messages {
-                let NetMessage::EngineMessage(engine_message) = message else {
-                    continue;
-                };
-                let EngineMessage::SvcVoiceData(ref svc_voice_data) = **engine_message else {
-                    continue;
-                };
-                let steam_voice_data = match SteamVoiceData::new(&svc_voice_data.data) {
-                    Ok(data) => data,
-                    Err(err) => panic!("Failed to parse steam voice data: {err}"),
-                };
-
-                let key = steam_voice_data.steam_id;
-
-                players.entry(key).or_insert_with(|| {
-                    PlayerStream::new(fmt_ctx, codec, bitrate)
-                        .expect("Creating player stream failed!")
-                });
-            }
+    /// What ffmpeg calls the headerless format of these samples
+    fn format_name(self) -> &'static str {
+        match self {
+            PcmFormat::S16 => "s16le",
+            PcmFormat::F32 => "f32le",
         }
     }
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(about, version)]
 struct Args {
-    /// Input demo file
-    #[arg(value_name = "input")]
-    input: String,
+    /// Input demo files, or - to read one from stdin, followed by the output audio file (left out
+    /// with --list-players, --list-formats, --dry-run and --check). A demo from stdin is read into
+    /// memory whole before it is parsed, so it briefly takes up about twice its size.
+    ///
+    /// Several demos are extracted on their own, one after the other. Each gets the output (and
+    /// any --srt, --vtt, --cue, --csv, --json or --metadata file) with the demo's name inserted
+    /// before the extension (out.wav -> out.<demo>.wav), and demos that fail are skipped.
+    ///
+    /// `.wav` outputs are written natively as 16-bit PCM unless a codec or format is given. `-`
+    /// streams the output to stdout through ffmpeg, as Ogg (or Matroska with -c) unless -f says
+    /// otherwise
+    #[arg(value_name = "input... output", num_args = 1.., required = true)]
+    paths: Vec<String>,
+
+    /// Input demos, taken from the paths
+    #[arg(skip)]
+    inputs: Vec<String>,
+
+    /// Output audio file, the last of the paths unless no output is written
+    #[arg(skip)]
+    output: Option<String>,
+
+    /// Play every input back one after the other into the same outputs instead, for matches
+    /// recorded across several files. Every demo is parsed up front, so they all have to fit in
+    /// memory at once
+    #[arg(long)]
+    merge: bool,
 
     /// Codec to use for audio encoding. Infered from output format if not included
     #[arg(short = 'c', value_name = "codec")]
     c: Option<String>,
 
-    /// Audio bitrate for encoder (when relevant)
-    #[arg(short = 'b', value_name = "bitrate")]
+    /// Audio bitrate for encoder (when relevant), in bits per second or with a k or M suffix like
+    /// 64k. The encoder's own default is used if not given
+    #[arg(short = 'b', value_name = "bitrate", value_parser = parse_bitrate)]
     b: Option<i64>,
 
+    /// Set an option of the encoder, like application=voip for opus. Can be given multiple times
+    #[arg(long, value_name = "key=value", value_parser = parse_codec_opt)]
+    codec_opt: Vec<(String, String)>,
+
+    /// Set an option of the resampler used when the encoder needs another sample format or rate,
+    /// like filter_size=64 or dither_method=triangular. Can be given multiple times
+    #[arg(
+        long,
+        visible_alias = "resample-quality",
+        value_name = "key=value",
+        value_parser = parse_codec_opt
+    )]
+    resample_opt: Vec<(String, String)>,
+
+    /// Encode at this sample rate instead of the rate voice is decoded at (usually 24kHz), like
+    /// 48000 for editors that dislike 24kHz. The encoder has to support it
+    #[arg(long, value_name = "Hz", value_parser = clap::value_parser!(i32).range(1..))]
+    rate: Option<i32>,
+
+    /// Give the encoder samples in this format, like s16, flt or s32p, instead of picking one
+    /// itself. The encoder has to support it
+    #[arg(long, value_name = "fmt")]
+    sample_fmt: Option<String>,
+
     /// Output format. Infered from output file name extension if not included
     #[arg(short = 'f', value_name = "fmt")]
     f: Option<String>,
 
-    /// Output audio file
-    #[arg(value_name = "output")]
-    output: String,
+    /// Write one output file per player, named after the output file with the player's name (or
+    /// steam id, if the demo doesn't record one) inserted before the extension
+    /// (out.wav -> out.<name>.wav)
+    #[arg(long)]
+    split: bool,
+
+    /// Mix every player down into a single mono track instead of writing one stream (or WAV
+    /// channel) per player. Players that aren't talking add silence, so they don't drown out
+    /// the others
+    #[arg(long, conflicts_with = "split")]
+    mix: bool,
+
+    /// Write every player as a track of their own in the one output, titled after them. That is
+    /// what happens without --mix or --split anyway, so this only spells it out
+    #[arg(long, conflicts_with_all = ["mix", "split", "dump_pcm", "raw_opus"])]
+    multitrack: bool,
+
+    /// Mix down into stereo, spreading players evenly from left to right. Players can be placed
+    /// explicitly with comma separated steamid=position pairs, from -1.0 (left) to 1.0 (right),
+    /// like --pan=STEAM_0:1:2=-0.5,STEAM_0:0:3=0.5
+    #[arg(
+        long,
+        visible_aliases = ["stereo-pan", "pan-for"],
+        value_name = "steamid=position",
+        num_args = 0..,
+        require_equals = true,
+        value_delimiter = ',',
+        value_parser = parse_pan,
+        requires = "mix"
+    )]
+    pan: Option<Vec<(u64, f32)>>,
+
+    /// Weigh one player in the mix by this factor, as steamid=factor: 0.5 halves what they add
+    /// to it, 2 doubles it, on top of any --gain. For taming a blown out microphone that drowns
+    /// out everyone else. Can be given multiple times
+    #[arg(
+        long,
+        value_name = "steamid=factor",
+        value_parser = parse_mix_weight,
+        requires = "mix"
+    )]
+    mono_sum_weight: Vec<(u64, f32)>,
+
+    /// Normalize every player to this integrated loudness (EBU R128), -16 LUFS if no target is
+    /// given as --normalize=LUFS. Decodes all voice data an extra time to measure it before
+    /// encoding
+    #[arg(
+        long,
+        visible_alias = "target-lufs",
+        value_name = "LUFS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "-16",
+        allow_negative_numbers = true
+    )]
+    normalize: Option<f64>,
+
+    /// Amplify (or attenuate, if negative) every player by this many dB. Samples are clipped
+    /// instead of overflowing
+    #[arg(
+        long,
+        value_name = "dB",
+        default_value = "0",
+        allow_negative_numbers = true
+    )]
+    gain: f32,
+
+    /// Use this gain instead of --gain for one player, as steamid=dB. Can be given multiple times
+    #[arg(long, value_name = "steamid=dB", value_parser = parse_gain_for)]
+    gain_for: Vec<(u64, f32)>,
+
+    /// Shift every player's voice by this many milliseconds, to sync it with a separately
+    /// recorded video: positive delays it, negative plays it earlier and drops what would start
+    /// before the output does
+    #[arg(
+        long,
+        value_name = "ms",
+        default_value = "0",
+        allow_negative_numbers = true
+    )]
+    offset: f32,
+
+    /// Use this offset instead of --offset for one player, as steamid=ms. Can be given multiple
+    /// times
+    #[arg(long, value_name = "steamid=ms", value_parser = parse_offset_for)]
+    offset_for: Vec<(u64, f32)>,
+
+    /// Cut leading and trailing silence out of every player's stream. Players no longer line up
+    /// with each other, so this needs a stream per player (no --mix, and --split for WAV)
+    #[arg(long)]
+    trim_silence: bool,
+
+    /// Make every player's track span the whole demo (or --start to --end), so split files line
+    /// up when dropped on a timeline. Tracks already do unless silence is trimmed, so this only
+    /// spells it out
+    #[arg(long, conflicts_with = "trim_silence")]
+    pad_to_demo_end: bool,
+
+    /// Seconds of silence kept around speech when trimming silence
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value = "0.2",
+        requires = "trim_silence"
+    )]
+    silence_padding: f32,
+
+    /// Also cut gaps between speech longer than this many seconds down to the padding when
+    /// trimming silence
+    #[arg(long, value_name = "seconds", requires = "trim_silence")]
+    max_gap: Option<f32>,
+
+    /// Also count samples at or below this level in dBFS (e.g. -60) as silence when trimming it,
+    /// instead of only digital silence
+    #[arg(
+        long,
+        value_name = "dB",
+        requires = "trim_silence",
+        allow_negative_numbers = true
+    )]
+    silence_threshold: Option<f32>,
+
+    /// Seconds of voice every player buffers before it starts playing. Raise it for demos from
+    /// laggy servers where speech comes out choppy, lower it for tighter sync: speech starts this
+    /// much after it arrived in the demo, and at 0 right away
+    #[arg(
+        long,
+        visible_alias = "initial-pad",
+        value_name = "seconds",
+        default_value_t = DEFAULT_JITTER_BUFFER_SECONDS,
+        value_parser = parse_seconds
+    )]
+    jitter_buffer: f32,
+
+    /// Fade every burst of speech in over this many milliseconds, instead of starting abruptly
+    #[arg(long, value_name = "ms", default_value = "0")]
+    fade_in: u32,
+
+    /// Fade every burst of speech out over this many milliseconds before the player goes quiet
+    #[arg(long, value_name = "ms", default_value = "0")]
+    fade_out: u32,
+
+    /// Run a high-pass filter with this cutoff over every player's voice, to take out microphone
+    /// rumble. Something like 80 leaves speech alone
+    #[arg(long, value_name = "Hz")]
+    highpass: Option<f32>,
+
+    /// Conceal at most this many lost voice frames in a row, up to 50. Longer gaps are left
+    /// silent, so speech after them still starts on time
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = DEFAULT_MAX_PLC_FRAMES,
+        value_parser = parse_max_plc
+    )]
+    max_plc: usize,
+
+    /// Only extract voice from this many seconds into the demo onwards. Outputs start here
+    #[arg(long, value_name = "seconds", value_parser = parse_seconds)]
+    start: Option<f32>,
+
+    /// Only extract voice up to this many seconds into the demo
+    #[arg(long, value_name = "seconds", value_parser = parse_seconds)]
+    end: Option<f32>,
+
+    /// Only extract the players with these steam ids, either 64-bit or STEAM_X:Y:Z. Can be
+    /// comma separated or given multiple times
+    #[arg(
+        long = "player",
+        visible_alias = "only",
+        value_name = "steamid",
+        value_delimiter = ',',
+        value_parser = parse_steam_id
+    )]
+    players: Vec<u64>,
+
+    /// Extract everyone except the players with these steam ids, like --player. Cannot be
+    /// combined with --player, since it would either be redundant or exclude everyone
+    #[arg(
+        long,
+        value_name = "steamid",
+        value_delimiter = ',',
+        value_parser = parse_steam_id,
+        conflicts_with = "players"
+    )]
+    exclude: Vec<u64>,
+
+    /// Print every player with voice data (steam id, message count, first and last time they
+    /// spoke, name) and exit without encoding anything
+    #[arg(long)]
+    list_players: bool,
+
+    /// Print every encoder (for -c) and output format (for -f) the linked ffmpeg has and exit.
+    /// Audio encoders and formats that take audio are marked with an A
+    #[arg(long, visible_alias = "format-list")]
+    list_formats: bool,
+
+    /// Encode up to this many players at once. Anything above 1 buffers every player's decoded
+    /// audio for the whole demo in memory before encoding
+    #[arg(
+        short = 'j',
+        long,
+        visible_alias = "threads",
+        value_name = "N",
+        default_value = "1"
+    )]
+    jobs: NonZeroUsize,
+
+    /// Write the output natively as 16-bit PCM WAV, whatever its extension
+    #[arg(
+        long,
+        conflicts_with_all = ["c", "f", "codec_opt", "resample_opt", "rate", "sample_fmt"]
+    )]
+    raw_wav: bool,
+
+    /// Write bare interleaved little-endian samples without any container or header. The sample
+    /// rate, channel count and format are printed to stderr. s16 unless given as --raw-pcm=format
+    #[arg(
+        long,
+        value_name = "format",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "s16",
+        conflicts_with_all = [
+            "c", "f", "codec_opt", "resample_opt", "rate", "sample_fmt", "raw_wav"
+        ]
+    )]
+    raw_pcm: Option<PcmFormat>,
+
+    /// Same as --raw-pcm --split, writing a headerless file per player
+    #[arg(
+        long,
+        value_name = "format",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "s16",
+        conflicts_with_all = [
+            "raw_pcm", "mix", "c", "f", "codec_opt", "resample_opt", "rate", "sample_fmt",
+            "raw_wav"
+        ]
+    )]
+    dump_pcm: Option<PcmFormat>,
+
+    /// Channel layout of every player's stream and of an unpanned mix. stereo writes the voice
+    /// into both channels, for players and editors that mishandle mono. Shared WAV and PCM files
+    /// already have a channel per player and are left alone
+    #[arg(long, value_name = "layout", default_value = "mono")]
+    channels: Channels,
+
+    /// Same as --channels stereo
+    #[arg(long, conflicts_with = "channels")]
+    stereo: bool,
+
+    /// Copy every player's opus voice data into their own Ogg Opus file as it was sent, without
+    /// re-encoding it. Files are named like with --split. The audio can't be processed, and lines
+    /// up with the demo to the nearest 20ms
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "c", "f", "codec_opt", "resample_opt", "rate", "sample_fmt", "raw_wav", "raw_pcm",
+            "dump_pcm", "mix", "normalize", "gain", "gain_for", "offset", "offset_for", "fade_in",
+            "fade_out", "highpass", "trim_silence", "channels", "stereo"
+        ]
+    )]
+    raw_opus: bool,
+
+    /// Add a chapter for every time a player was talking, named after them, to Matroska and MP4
+    /// outputs. With --split every file only gets chapters for its own player
+    #[arg(
+        long,
+        conflicts_with_all = ["raw_wav", "raw_pcm", "dump_pcm", "raw_opus", "trim_silence"]
+    )]
+    chapters: bool,
+
+    /// Also write an SRT subtitle file with a [name] cue for every time a player was talking,
+    /// timed to line up with the audio. Works with any output
+    #[arg(long, value_name = "path")]
+    srt: Option<PathBuf>,
+
+    /// Also write a WebVTT subtitle file, like --srt, for HTML5 players. Every cue is a voice span
+    /// of the player talking
+    #[arg(long, value_name = "path")]
+    vtt: Option<PathBuf>,
+
+    /// Pauses in speech shorter than this many seconds don't split a subtitle cue of --srt or
+    /// --vtt
+    #[arg(long, value_name = "seconds", default_value = "0.5")]
+    srt_merge_gap: f64,
+
+    /// Also write a CUE sheet for the output with a track every time another player starts
+    /// talking. The output is referenced by its file name, so keep both in one directory
+    #[arg(long, value_name = "path", conflicts_with_all = ["split", "raw_opus"])]
+    cue: Option<PathBuf>,
+
+    /// Also write a CSV file with a steam_id,start_seconds,end_seconds,duration_seconds row for
+    /// every time a player was talking. Works with any output
+    #[arg(long, value_name = "path")]
+    csv: Option<PathBuf>,
+
+    /// Also write a JSON file describing the run: input, outputs, codec, format, sample rate and
+    /// what every player contributed
+    #[arg(long, value_name = "path")]
+    json: Option<PathBuf>,
+
+    /// Also write a JSON file describing every extracted player: steam id, name, how long they
+    /// talked, how many opus packets were decoded and concealed, how often their decoder was
+    /// reset, and when they first and last spoke
+    #[arg(long, value_name = "path")]
+    metadata: Option<PathBuf>,
+
+    /// Decode and play back every player like a real run, then print what --stats would, without
+    /// creating the output or any side file. Demo and decoding problems still show up
+    #[arg(long, conflicts_with = "raw_opus")]
+    dry_run: bool,
+
+    /// Only decode the voice of every player, without playing it back or writing anything, and
+    /// fail if any of it doesn't decode. Quicker than --dry-run for checking a batch of demos
+    #[arg(long, conflicts_with_all = ["raw_opus", "dry_run"])]
+    check: bool,
+
+    /// Print how decoding went for every player once done: voiced seconds, opus packets decoded,
+    /// lost and concealed, how often their decoder was reset, and the peak and RMS level of their
+    /// voice before any gain. Lots of resets or lost packets point at a glitchy recording rather
+    /// than a decoding problem
+    #[arg(long)]
+    stats: bool,
+
+    /// Only print errors: no progress, warnings or information. Progress is only shown when
+    /// stderr is a terminal either way
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print what is going on in more detail: -v for debugging, -vv for everything. Without it,
+    /// the level is taken from RUST_LOG (error, warn, info, debug, trace or off)
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+impl Args {
+    /// Splits the positional paths into the inputs and the output, exiting like clap does if the
+    /// output is missing, or given when nothing is written
+    fn split_paths(&mut self) {
+        let mut paths = std::mem::take(&mut self.paths);
+        if self.list_players || self.dry_run || self.check {
+            // Otherwise the output would be taken for another demo and extracted on its own
+            if let [_, .., last] = paths.as_slice()
+                && last != STDIN_INPUT
+                && !Path::new(last)
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("dem"))
+            {
+                Args::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!(
+                            "{last} isn't a demo, --list-players, --dry-run and --check don't \
+                             write an output to give"
+                        ),
+                    )
+                    .exit();
+            }
+        } else if !self.list_formats {
+            if paths.len() < 2 {
+                Args::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "the output is required after the input demos",
+                    )
+                    .exit();
+            }
+            self.output = paths.pop();
+        }
+        self.inputs = paths;
+    }
+}
 
-    let maybe_format_name = args.f.map(|f| CString::new(f).unwrap());
+/// Steam id of the first individual account, what the account number of STEAM_X:Y:Z ids counts up
+/// from
+const STEAM_ID_64_BASE: u64 = 76561197960265728;
 
-    let mut fmt_ctx = AVFormatContextOutput::builder()
-        .maybe_format_name(maybe_format_name.as_deref())
-        .filename(CString::new(args.output).unwrap().as_c_str())
-        .build()?;
+/// Parses a steam id, either as its 64-bit value or in the STEAM_X:Y:Z form
+fn parse_steam_id(arg: &str) -> Result<u64, String> {
+    let Some(textual) = arg.strip_prefix("STEAM_") else {
+        return arg.parse().map_err(|e| format!("invalid steam id: {e}"));
+    };
+    // The universe (X) is 0 in older games for what is the public universe everywhere else
+    let mut parts = textual.split(':');
+    let (Some(universe), Some(y), Some(z), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err("expected STEAM_X:Y:Z".into());
+    };
+    if !matches!(universe, "0" | "1") {
+        return Err("only steam ids of the public universe are supported".into());
+    }
+    let y: u64 = match y {
+        "0" => 0,
+        "1" => 1,
+        _ => return Err("the Y of STEAM_X:Y:Z must be 0 or 1".into()),
+    };
+    let z: u64 = z
+        .parse()
+        .map_err(|e| format!("invalid account number: {e}"))?;
+    if z >= 1 << 31 {
+        return Err("account number is out of range".into());
+    }
+    Ok(STEAM_ID_64_BASE + z * 2 + y)
+}
 
-    let codec = if let Some(codec) = args.c {
-        AVCodec::find_encoder_by_name(CString::new(codec).unwrap().as_c_str())
-            .ok_or("Encoder does not exist")?
-    } else {
-        AVCodec::find_encoder(fmt_ctx.oformat().audio_codec)
-            .expect("Couldn't find encoder from default id!")
+/// Parses a non-negative number of seconds
+fn parse_seconds(arg: &str) -> Result<f32, String> {
+    let seconds: f32 = arg.parse().map_err(|e| format!("invalid number: {e}"))?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err("must be a finite number that isn't negative".into());
+    }
+    Ok(seconds)
+}
+
+/// Parses how many frames --max-plc conceals, which can't be more than PLC plausibly covers
+fn parse_max_plc(arg: &str) -> Result<usize, String> {
+    let frames: usize = arg.parse().map_err(|e| format!("invalid number: {e}"))?;
+    if frames > MAX_PLC_FRAMES {
+        return Err(format!(
+            "can't conceal more than {MAX_PLC_FRAMES} frames in a row"
+        ));
+    }
+    Ok(frames)
+}
+
+/// Parses a steamid=dB pair given to --gain-for
+fn parse_gain_for(arg: &str) -> Result<(u64, f32), String> {
+    let (steam_id, gain) = arg.split_once('=').ok_or("expected steamid=dB")?;
+    let gain = gain.parse().map_err(|e| format!("invalid gain: {e}"))?;
+    Ok((parse_steam_id(steam_id)?, gain))
+}
+
+/// Parses a steamid=ms pair given to --offset-for
+fn parse_offset_for(arg: &str) -> Result<(u64, f32), String> {
+    let (steam_id, offset) = arg.split_once('=').ok_or("expected steamid=ms")?;
+    let offset: f32 = offset.parse().map_err(|e| format!("invalid offset: {e}"))?;
+    Ok((parse_steam_id(steam_id)?, offset))
+}
+
+/// Parses a bitrate given to -b, like 128000, 64k or 1.5M
+fn parse_bitrate(arg: &str) -> Result<i64, String> {
+    let (number, multiplier) = match arg.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&arg[..index], 1e3),
+        Some((index, 'M' | 'm')) => (&arg[..index], 1e6),
+        _ => (arg, 1.0),
     };
+    let bitrate: f64 = number
+        .parse()
+        .map_err(|e| format!("invalid bitrate: {e}"))?;
+    let bitrate = (bitrate * multiplier).round();
+    if !(1.0..=i32::MAX as f64).contains(&bitrate) {
+        return Err("bitrate must be a positive number of bits per second".into());
+    }
+    Ok(bitrate as i64)
+}
 
-    let demo = open_demo(args.input)?;
-    let mut players: HashMap<u64, PlayerStream> = HashMap::new();
-    discover_players(&mut players, &demo, &mut fmt_ctx, &codec, args.b);
-
-    fmt_ctx.write_header(&mut None)?;
-    let mut last_frame_time: Option<f32> = None;
-
-    for entry in demo.directory.entries {
-        if entry.type_ == 0 {
-            continue;
-        } // nEntryType == DEMO_STARTUP
-
-        for demo_frame in entry.frames {
-            'voice: {
-                let FrameData::NetworkMessage(boxed_network_message) = demo_frame.frame_data else {
-                    break 'voice;
-                };
-                let network_message = boxed_network_message.1;
-                let MessageData::Parsed(messages) = network_message.messages else {
-                    break 'voice;
-                };
-                for message in messages {
-                    let NetMessage::EngineMessage(engine_message) = message else {
-                        continue;
-                    };
-                    let EngineMessage::SvcVoiceData(svc_voice_data) = *engine_message else {
-                        continue;
-                    };
-                    let Ok(steam_voice_data) = SteamVoiceData::new(&svc_voice_data.data) else {
-                        eprintln!("Failed to parse svc_voice_data as steam voice data!");
-                        continue;
-                    };
-
-                    let key = steam_voice_data.steam_id;
-
-                    let player_stream = players
-                        .get_mut(&key)
-                        .expect("Player stream for found id didn't exist!");
-
-                    // goldsrc interally uses a buffer half this size, but it also has a little
-                    // less than half the sample rate. this calculation has always worked, so...
-                    let mut tmp = vec![0u8; 8192 * player_stream.bytes_per_sample];
-                    match player_stream.decoder.decode(steam_voice_data, &mut tmp) {
-                        Ok(samples_written) => {
-                            player_stream.append_samples(tmp.iter().take(samples_written).copied());
-                        }
-                        Err(e) => {
-                            eprintln!("Decoder error: {:?}", e);
-                        }
-                    }
-                }
-            }
+/// Parses a key=value pair given to --codec-opt
+fn parse_codec_opt(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = arg.split_once('=').ok_or("expected key=value")?;
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// Looks up a sample format given to --sample-fmt by its ffmpeg name
+#[cfg(feature = "ffmpeg")]
+fn sample_format(name: &str) -> Result<i32, Box<dyn std::error::Error>> {
+    rsmpeg::avutil::get_sample_fmt(&CString::new(name)?)
+        .ok_or_else(|| format!("Unknown sample format {name}, like s16, flt or s32p").into())
+}
+
+/// Parses a steamid=position pair given to --pan
+fn parse_pan(arg: &str) -> Result<(u64, f32), String> {
+    let (steam_id, position) = arg.split_once('=').ok_or("expected steamid=position")?;
+    let steam_id = parse_steam_id(steam_id)?;
+    let position: f32 = position
+        .parse()
+        .map_err(|e| format!("invalid position: {e}"))?;
+    if !(-1.0..=1.0).contains(&position) {
+        return Err("position must be between -1.0 and 1.0".into());
+    }
+    Ok((steam_id, position))
+}
+
+/// Parses a steamid=factor pair given to --mono-sum-weight
+fn parse_mix_weight(arg: &str) -> Result<(u64, f32), String> {
+    let (steam_id, weight) = arg.split_once('=').ok_or("expected steamid=factor")?;
+    let steam_id = parse_steam_id(steam_id)?;
+    let weight: f32 = weight.parse().map_err(|e| format!("invalid weight: {e}"))?;
+    if !weight.is_finite() || weight < 0.0 {
+        return Err("weight must be a finite number that isn't negative".into());
+    }
+    Ok((steam_id, weight))
+}
+
+/// Replaces characters that aren't safe in file names
+fn sanitize_label(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() || c == '-' || c == '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Inserts `label` before the extension of `output`
+fn split_output_path(output: &Path, label: &str) -> PathBuf {
+    let mut file_name = output.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(label);
+    if let Some(extension) = output.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    output.with_file_name(file_name)
+}
+
+/// Names the files of a split output after the players in them
+struct SplitPaths {
+    output: PathBuf,
+    used_labels: HashSet<String>,
+    /// Every path handed out so far
+    created: Vec<PathBuf>,
+}
+
+impl SplitPaths {
+    fn new(output: impl Into<PathBuf>) -> Self {
+        Self {
+            output: output.into(),
+            used_labels: HashSet::new(),
+            created: Vec::new(),
+        }
+    }
+
+    fn path(&mut self, steam_id: u64, name: &str) -> PathBuf {
+        let mut label = sanitize_label(name);
+        if !self.used_labels.insert(label.clone()) {
+            // Two players share a name, keep their files apart
+            label = format!("{label}_{steam_id}");
+        }
+        let path = split_output_path(&self.output, &label);
+        self.created.push(path.clone());
+        path
+    }
+}
+
+/// Writes log records to stderr, prefixed with their level unless they're plain information
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            log::Level::Error => eprintln!("Error: {}", record.args()),
+            log::Level::Warn => eprintln!("Warning: {}", record.args()),
+            log::Level::Info => eprintln!("{}", record.args()),
+            log::Level::Debug => eprintln!("[debug] {}", record.args()),
+            log::Level::Trace => eprintln!("[trace] {}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Name and version written into the metadata of every container
+#[cfg(feature = "ffmpeg")]
+const ENCODING_TOOL: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// Exit code for demos without any voice data, so scripts can tell them apart from failures
+const EXIT_NO_VOICE_DATA: u8 = 2;
+
+/// Parses a single demo, from stdin if `path` is [`STDIN_INPUT`]
+fn open_input(path: &str) -> Result<Demo, Box<dyn std::error::Error>> {
+    if path == STDIN_INPUT {
+        Ok(read_demo(std::io::stdin().lock()).map_err(|e| format!("stdin: {e}"))?)
+    } else {
+        Ok(open_demo(path).map_err(|e| format!("{path}: {e}"))?)
+    }
+}
+
+/// Opens the input demos as one, played back one after the other
+fn open_demos(inputs: &[String]) -> Result<Demo, Box<dyn std::error::Error>> {
+    if inputs.iter().filter(|&path| path == STDIN_INPUT).count() > 1 {
+        return Err("Only one demo can be read from stdin".into());
+    }
+    let demos = inputs
+        .iter()
+        .map(|path| open_input(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut demos = demos.into_iter();
+    let demo = demos.next().expect("there is always an input");
+    Ok(concat_demos(demo, demos))
+}
+
+/// Opens the demos to extract voice from, failing before any output is created if they have none
+fn open_voice_demo(inputs: &[String]) -> Result<Demo, Box<dyn std::error::Error>> {
+    let demo = open_demos(inputs)?;
+    check_voice_data(&demo)?;
+    Ok(demo)
+}
+
+fn main() -> ExitCode {
+    let mut args = Args::parse();
+    args.split_paths();
+    if let Some(pcm_format) = args.dump_pcm {
+        args.raw_pcm = Some(pcm_format);
+        args.split = true;
+    }
+
+    log::set_logger(&StderrLogger).expect("the logger is only set once");
+    // Only a plain level is understood from RUST_LOG, not per module filters
+    let env_level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok());
+    log::set_max_level(match args.verbose {
+        _ if args.quiet => log::LevelFilter::Error,
+        0 => env_level.unwrap_or(log::LevelFilter::Info),
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    });
 
-            let frametime = if let Some(prev) = last_frame_time {
-                (demo_frame.time - prev).max(0.0)
-            } else {
-                0.0
-            };
-            last_frame_time = Some(demo_frame.time);
-
-            // Although we're looping through parsed "frames", they're really just sections of
-            // information about a demo at a given time. These sections will group together on game
-            // frames, but there can be mutliple parsed demo "frames" in a game frame with
-            // different kinds of information about the game state. We use the frame rate
-            // to replicate the engine behavior with audio buffering, but we don't actually care
-            // about each frame
-            if frametime == 0.0 {
-                continue;
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) if e.is::<NoVoiceData>() => {
+            log::error!("{e}");
+            ExitCode::from(EXIT_NO_VOICE_DATA)
+        }
+        Err(e) => {
+            log::error!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    if args.inputs.len() > 1 && !args.merge && !args.list_formats {
+        return run_batch(args);
+    }
+    let inputs = args.inputs;
+    if args.list_players {
+        let demo = open_demos(&inputs)?;
+        // An empty list is fine, but not for voice that is there and can't be read
+        if let Err(e) = check_voice_data(&demo)
+            && e.is::<UnsupportedVoiceCodec>()
+        {
+            return Err(e);
+        }
+        let names = resolve_names(&demo);
+        let mut players: Vec<_> = scan_players(&demo).into_iter().collect();
+        players.sort_by_key(|&(steam_id, _)| steam_id);
+        for (steam_id, activity) in players {
+            print!(
+                "{steam_id} messages={} first={:.3} last={:.3}",
+                activity.message_count, activity.first_time, activity.last_time
+            );
+            match names.get(&steam_id) {
+                Some(name) => println!(" name={name}"),
+                None => println!(),
             }
+        }
+        return Ok(());
+    }
+    if args.list_formats {
+        return list_formats();
+    }
+    // Only a dry run goes without an output
+    let output = args.output.unwrap_or_default();
+
+    let players = if !args.players.is_empty() {
+        PlayerFilter::Only(args.players.into_iter().collect())
+    } else if !args.exclude.is_empty() {
+        PlayerFilter::Exclude(args.exclude.into_iter().collect())
+    } else {
+        PlayerFilter::All
+    };
+    let pan: Option<HashMap<u64, f32>> = args.pan.map(|pan| pan.into_iter().collect());
+
+    if output == STDOUT_OUTPUT {
+        if args.split || args.raw_opus {
+            return Err("A split output can't be written to stdout".into());
+        }
+        if args.cue.is_some() {
+            return Err("A CUE sheet needs an output file to refer to".into());
+        }
+        // The native writers only write files
+        if args.raw_wav || args.raw_pcm.is_some() {
+            return Err(
+                "Raw WAV and PCM can't be written to stdout, use -f wav or -f s16le instead".into(),
+            );
+        }
+    }
+
+    // Plain WAV doesn't need ffmpeg at all, so it's written natively unless the user asked for a
+    // specific codec, format, sample rate, sample format or encoder or resampler options
+    let native_wav = args.raw_wav
+        || args.c.is_none()
+            && args.codec_opt.is_empty()
+            && args.resample_opt.is_empty()
+            && args.rate.is_none()
+            && args.sample_fmt.is_none()
+            && args.f.as_deref().is_none_or(|f| f == "wav")
+            && Path::new(&output)
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("wav"));
+    let cue_file = Path::new(&output)
+        .file_name()
+        .map_or(output.clone(), |name| name.to_string_lossy().into_owned());
+    let config = ExtractConfig {
+        #[cfg(feature = "ffmpeg")]
+        codec: None,
+        bitrate: args.b,
+        #[cfg(feature = "ffmpeg")]
+        codec_options: args.codec_opt,
+        #[cfg(feature = "ffmpeg")]
+        resample_options: args.resample_opt,
+        #[cfg(feature = "ffmpeg")]
+        encoder_rate: args.rate,
+        #[cfg(feature = "ffmpeg")]
+        encoder_format: args.sample_fmt.as_deref().map(sample_format).transpose()?,
+        #[cfg(feature = "ffmpeg")]
+        chapters: args.chapters,
+        players,
+        mix: args.mix,
+        pan,
+        mix_weights: args.mono_sum_weight.into_iter().collect(),
+        normalize: args.normalize,
+        gain: args.gain,
+        player_gains: args.gain_for.into_iter().collect(),
+        offset: args.offset / 1000.0,
+        player_offsets: args
+            .offset_for
+            .into_iter()
+            .map(|(steam_id, offset)| (steam_id, offset / 1000.0))
+            .collect(),
+        trim_silence: args.trim_silence.then_some(TrimSilence {
+            padding: args.silence_padding,
+            max_gap: args.max_gap,
+            threshold: args.silence_threshold,
+        }),
+        start: args.start,
+        end: args.end,
+        jobs: args.jobs,
+        jitter_buffer: args.jitter_buffer,
+        max_plc_frames: args.max_plc,
+        fade_in: args.fade_in as f32 / 1000.0,
+        fade_out: args.fade_out as f32 / 1000.0,
+        highpass: args.highpass,
+        dual_mono: args.stereo || matches!(args.channels, Channels::Stereo),
+        progress: !args.quiet && std::io::stderr().is_terminal(),
+    };
+    if args.dry_run {
+        let demo = open_voice_demo(&inputs)?;
+        let summary = extract_owned(demo, &config, Output::Discard)?;
+        print_stats(&summary);
+        return Ok(());
+    }
+    if args.check {
+        let demo = open_voice_demo(&inputs)?;
+        let check = check_voice(&demo, &config)?;
+        let mut players: Vec<_> = check.players.values().collect();
+        players.sort_by(|a, b| a.name.cmp(&b.name));
+        for player in players {
+            println!(
+                "{}: {} packets, {} lost, {} resets, {} failed to decode",
+                player.name,
+                player.decoder.packets,
+                player.decoder.lost_packets,
+                player.decoder.resets,
+                player.errors
+            );
+        }
+        if !check.is_clean() {
+            let errors: usize = check.players.values().map(|player| player.errors).sum();
+            return Err(format!(
+                "{errors} voice messages failed to decode and {} weren't steam voice data",
+                check.unparsed_messages
+            )
+            .into());
+        }
+        return Ok(());
+    }
+    if args.pad_to_demo_end {
+        log::debug!("Every track spans the whole demo already");
+    }
+    if args.multitrack && (native_wav || args.raw_pcm.is_some()) {
+        log::info!("WAV and PCM have no tracks, every player gets a channel instead");
+    }
+    if args.chapters && native_wav {
+        log::warn!("WAV can't carry chapters, leaving them out");
+    }
+    if args.b.is_some() && (native_wav || args.raw_pcm.is_some()) {
+        log::warn!("PCM has a fixed bitrate, ignoring -b");
+    }
+    let (summary, written) = if args.raw_opus {
+        let demo = open_voice_demo(&inputs)?;
+        let mut paths = SplitPaths::new(output);
+        let mut create_file = |steam_id: u64, name: &str| File::create(paths.path(steam_id, name));
+        let summary = extract_raw_opus(&demo, &config, &mut create_file)?;
+        (summary, Written::new("opus", "ogg").to(paths.created))
+    } else if let Some(pcm_format) = args.raw_pcm {
+        let sample_format = pcm_format.sample_format();
+        let written = Written::new(pcm_format.codec_name(), pcm_format.format_name());
+        let demo = open_voice_demo(&inputs)?;
+        if args.split {
+            let mut paths = SplitPaths::new(output);
+            let mut create_file =
+                |steam_id: u64, name: &str| File::create(paths.path(steam_id, name));
+            let summary = extract_owned(
+                demo,
+                &config,
+                Output::PcmSplit(&mut create_file, sample_format),
+            )?;
+            (summary, written.to(paths.created))
+        } else {
+            let summary = extract_owned(
+                demo,
+                &config,
+                Output::Pcm(File::create(&output)?, sample_format),
+            )?;
+            (summary, written.to(vec![output.into()]))
+        }
+    } else if native_wav {
+        let written = Written::new("pcm_s16le", "wav");
+        let demo = open_voice_demo(&inputs)?;
+        if args.split {
+            let mut paths = SplitPaths::new(output);
+            let mut create_file =
+                |steam_id: u64, name: &str| File::create(paths.path(steam_id, name));
+            let summary = extract_owned(demo, &config, Output::WavSplit(&mut create_file))?;
+            (summary, written.to(paths.created))
+        } else {
+            let summary = extract_owned(demo, &config, Output::Wav(File::create(&output)?))?;
+            (summary, written.to(vec![output.into()]))
+        }
+    } else {
+        extract_with_ffmpeg(&inputs, output, args.c, args.f, args.split, config)?
+    };
+
+    if let Some(path) = args.srt {
+        let cues = speech_cues(&summary, args.srt_merge_gap);
+        write_srt(BufWriter::new(File::create(path)?), &cues)?;
+    }
+    if let Some(path) = args.vtt {
+        let cues = speech_cues(&summary, args.srt_merge_gap);
+        write_vtt(BufWriter::new(File::create(path)?), &cues)?;
+    }
+    if let Some(path) = args.cue {
+        write_cue(BufWriter::new(File::create(path)?), &summary, &cue_file)?;
+    }
+    if let Some(path) = args.csv {
+        write_speech_csv(BufWriter::new(File::create(path)?), &summary)?;
+    }
+    if let Some(path) = args.json {
+        let sidecar = sidecar_json(&inputs, &written, &summary);
+        std::fs::write(path, format!("{sidecar}\n"))?;
+    }
+    if let Some(path) = args.metadata {
+        std::fs::write(path, format!("{}\n", player_metadata_json(&summary)))?;
+    }
+    if args.stats {
+        print_stats(&summary);
+    }
+    Ok(())
+}
+
+/// `level` as a fraction of full scale in dBFS, `None` for digital silence
+fn dbfs(level: f32) -> Option<f64> {
+    (level > 0.0).then(|| 20.0 * f64::from(level).log10())
+}
+
+/// Prints how decoding went for every player, ordered by steam id
+fn print_stats(summary: &ExtractSummary) {
+    let mut players: Vec<_> = summary.players.iter().collect();
+    players.sort_by_key(|&(&steam_id, _)| steam_id);
+    for (steam_id, player) in players {
+        let voiced: f64 = player
+            .speech
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum();
+        let stats = player.decoder;
+        let level = |level: f32| dbfs(level).map_or("-inf".to_string(), |db| format!("{db:.1}"));
+        eprintln!(
+            "{} ({steam_id}): {voiced:.1}s voiced, {} opus packets decoded, {} lost, {} concealed, \
+             {} decoder resets, peak {} dBFS, RMS {} dBFS",
+            player.name,
+            stats.packets,
+            stats.lost_packets,
+            stats.concealed_frames,
+            stats.resets,
+            level(player.peak),
+            level(player.rms)
+        );
+    }
+}
+
+/// What a run wrote and how, for the --json sidecar
+struct Written {
+    codec: String,
+    format: String,
+    outputs: Vec<PathBuf>,
+}
+
+impl Written {
+    fn new(codec: &str, format: &str) -> Self {
+        Self {
+            codec: codec.to_owned(),
+            format: format.to_owned(),
+            outputs: Vec::new(),
+        }
+    }
 
-            let demo_frame_time_as_pts = (demo_frame.time * SAMPLE_RATE as f32).floor() as i64;
-
-            for (_id, player_stream) in players.iter_mut() {
-                if player_stream.time_pad > 0.0 && player_stream.buffered_samples() != 0 {
-                    player_stream.time_pad -= frametime;
-                    if player_stream.time_pad <= 0.0 {
-                        player_stream.playing = true;
-                    }
-                }
-
-                let demo_frame_sample_count =
-                    (demo_frame_time_as_pts - player_stream.last_demo_pts) as usize;
-                player_stream.last_demo_pts = demo_frame_time_as_pts;
-
-                let mut samples: Vec<u8> = player_stream.frame_accum.clone();
-
-                if player_stream.playing {
-                    samples.extend(player_stream.consume_samples(demo_frame_sample_count));
-                    if player_stream.buffered_samples() == 0 {
-                        player_stream.playing = false;
-                    }
-                } else {
-                    samples.extend(core::iter::repeat_n(
-                        0u8,
-                        demo_frame_sample_count * player_stream.bytes_per_sample,
-                    ));
-                };
-
-                let mut offset = 0;
-                let frame_size_bytes =
-                    player_stream.frame.nb_samples as usize * player_stream.bytes_per_sample;
-
-                while offset + frame_size_bytes <= samples.len() {
-                    let frame_slice = &samples[offset..offset + frame_size_bytes];
-
-                    let mut resampled_buf;
-                    let frame_data: &[u8] = if let Some(resampler) = &mut player_stream.resampler {
-                        resampled_buf = vec![0u8; frame_slice.len() * 4];
-                        let in_bufs = [frame_slice.as_ptr()];
-                        let mut out_bufs = [resampled_buf.as_mut_ptr()];
-
-                        let out_samples = unsafe {
-                            resampler.convert(
-                                out_bufs.as_mut_ptr(),
-                                player_stream.frame.nb_samples,
-                                in_bufs.as_ptr(),
-                                player_stream.frame.nb_samples,
-                            )?
-                        } as usize;
-
-                        &resampled_buf[..out_samples * player_stream.enc_bytes_per_sample]
-                    } else {
-                        frame_slice
-                    };
-
-                    unsafe {
-                        std::ptr::copy_nonoverlapping(
-                            frame_data.as_ptr(),
-                            player_stream.frame.data[0],
-                            frame_data.len(),
-                        );
-                    }
-
-                    player_stream.frame.set_pts(player_stream.pts);
-                    player_stream.pts += player_stream.frame.nb_samples as i64;
-                    player_stream
-                        .codec_ctx
-                        .send_frame(Some(&player_stream.frame))?;
-
-                    while let Ok(mut pkt) = player_stream.codec_ctx.receive_packet() {
-                        pkt.rescale_ts(
-                            player_stream.codec_ctx.time_base,
-                            fmt_ctx.streams()[player_stream.stream_index].time_base,
-                        );
-                        pkt.set_stream_index(player_stream.stream_index as i32);
-                        fmt_ctx.write_frame(&mut pkt)?;
-                    }
-
-                    offset += frame_size_bytes;
-                }
-
-                player_stream.frame_accum = Vec::from(&samples[offset..]);
+    fn to(self, outputs: Vec<PathBuf>) -> Self {
+        Self { outputs, ..self }
+    }
+}
+
+/// Extracts every input demo into its own outputs, carrying on past the ones that fail
+fn run_batch(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    if args.output.as_deref() == Some(STDOUT_OUTPUT) {
+        return Err("Several demos can't be written to stdout without --merge".into());
+    }
+    let inputs = &args.inputs;
+
+    let mut used_labels = HashSet::new();
+    let mut failed = 0;
+    for (index, input) in inputs.iter().enumerate() {
+        let mut label = match Path::new(input).file_stem() {
+            _ if input == STDIN_INPUT => "stdin".to_string(),
+            Some(stem) => sanitize_label(&stem.to_string_lossy()),
+            None => sanitize_label(input),
+        };
+        if !used_labels.insert(label.clone()) {
+            // Demos of the same name from different directories
+            label = format!("{label}_{index}");
+        }
+        let rename =
+            |path: &Option<PathBuf>| path.as_deref().map(|path| split_output_path(path, &label));
+        let job = Args {
+            inputs: vec![input.clone()],
+            output: args.output.as_ref().map(|output| {
+                split_output_path(Path::new(output), &label)
+                    .to_string_lossy()
+                    .into_owned()
+            }),
+            srt: rename(&args.srt),
+            vtt: rename(&args.vtt),
+            cue: rename(&args.cue),
+            csv: rename(&args.csv),
+            json: rename(&args.json),
+            metadata: rename(&args.metadata),
+            ..args.clone()
+        };
+        log::info!("Extracting {input}");
+        match run(job) {
+            Ok(()) => {}
+            Err(e) if e.is::<NoVoiceData>() => log::warn!("{input}: {e}, skipping it"),
+            Err(e) => {
+                log::error!("{input}: {e}");
+                failed += 1;
             }
         }
     }
+    if failed > 0 {
+        return Err(format!("{failed} of {} demos failed", inputs.len()).into());
+    }
+    Ok(())
+}
 
-    // Flush
-    for (_id, player_stream) in players.iter_mut() {
-        player_stream.codec_ctx.send_frame(None)?;
+/// Describes the whole run: what was read, what was written and what every player contributed
+fn sidecar_json(inputs: &[String], written: &Written, summary: &ExtractSummary) -> Json {
+    let mut players: Vec<_> = summary.players.iter().collect();
+    players.sort_by_key(|&(&steam_id, _)| steam_id);
+    let players = players.into_iter().map(|(&steam_id, player)| {
+        let speaking: f64 = player
+            .speech
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum();
+        Json::object([
+            ("steam_id", steam_id.into()),
+            ("name", player.name.as_str().into()),
+            ("decoded_samples", player.decoded_samples.into()),
+            ("speaking_seconds", speaking.into()),
+            ("lost_packets", player.decoder.lost_packets.into()),
+            ("peak_dbfs", dbfs(player.peak).into()),
+            ("rms_dbfs", dbfs(player.rms).into()),
+        ])
+    });
+    let outputs = written
+        .outputs
+        .iter()
+        .map(|path| path.to_string_lossy().into_owned().into());
+    Json::object([
+        ("input", inputs[0].as_str().into()),
+        (
+            "inputs",
+            Json::Array(inputs.iter().map(|path| path.as_str().into()).collect()),
+        ),
+        ("outputs", Json::Array(outputs.collect())),
+        ("codec", written.codec.as_str().into()),
+        ("format", written.format.as_str().into()),
+        ("sample_rate", i64::from(summary.sample_rate).into()),
+        ("players", Json::Array(players.collect())),
+    ])
+}
 
-        while let Ok(mut pkt) = player_stream.codec_ctx.receive_packet() {
-            pkt.rescale_ts(
-                player_stream.codec_ctx.time_base,
-                fmt_ctx.streams()[player_stream.stream_index].time_base,
+/// Describes every extracted player, ordered by steam id
+fn player_metadata_json(summary: &ExtractSummary) -> Json {
+    let mut players: Vec<_> = summary.players.iter().collect();
+    players.sort_by_key(|&(&steam_id, _)| steam_id);
+    let players = players.into_iter().map(|(&steam_id, player)| {
+        let voiced: f64 = player
+            .speech
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum();
+        Json::object([
+            ("steam_id", steam_id.into()),
+            ("name", player.name.as_str().into()),
+            ("voiced_seconds", voiced.into()),
+            ("opus_packets", player.decoder.packets.into()),
+            ("concealed_frames", player.decoder.concealed_frames.into()),
+            ("decoder_resets", player.decoder.resets.into()),
+            (
+                "first_spoke",
+                player.speech.first().map(|range| range.start).into(),
+            ),
+            (
+                "last_spoke",
+                player.speech.last().map(|range| range.end).into(),
+            ),
+        ])
+    });
+    Json::object([("players", Json::Array(players.collect()))])
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+fn list_formats() -> Result<(), Box<dyn std::error::Error>> {
+    Err("Built without ffmpeg support, only WAV output is available".into())
+}
+
+/// Prints the encoders and muxers of the ffmpeg this was linked against
+#[cfg(feature = "ffmpeg")]
+fn list_formats() -> Result<(), Box<dyn std::error::Error>> {
+    // Builds without descriptions leave long names unset
+    let describe = |long_name: *const std::ffi::c_char| {
+        if long_name.is_null() {
+            String::new()
+        } else {
+            // SAFETY: ffmpeg's descriptions are static, nul terminated strings
+            unsafe { std::ffi::CStr::from_ptr(long_name) }
+                .to_string_lossy()
+                .into_owned()
+        }
+    };
+    let mark = |audio: bool| if audio { 'A' } else { '-' };
+
+    println!("Encoders:");
+    let encoders =
+        AVCodec::iterate().filter(|codec| unsafe { ffi::av_codec_is_encoder(codec.as_ptr()) } != 0);
+    for codec in encoders {
+        println!(
+            " {} {:<24} {}",
+            mark(codec.type_ == ffi::AVMEDIA_TYPE_AUDIO),
+            codec.name().to_string_lossy(),
+            describe(codec.long_name)
+        );
+    }
+    println!("Formats:");
+    for format in AVOutputFormat::iterate() {
+        println!(
+            " {} {:<24} {}",
+            mark(format.audio_codec != ffi::AV_CODEC_ID_NONE),
+            format.name().to_string_lossy(),
+            describe(format.long_name)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+fn extract_with_ffmpeg(
+    _inputs: &[String],
+    _output: String,
+    _codec: Option<String>,
+    _format: Option<String>,
+    _split: bool,
+    _config: ExtractConfig,
+) -> Result<(ExtractSummary, Written), Box<dyn std::error::Error>> {
+    Err("Built without ffmpeg support, only WAV output is available".into())
+}
+
+/// Encodes the demo through ffmpeg, guessing the format from the output's extension unless one
+/// is given
+#[cfg(feature = "ffmpeg")]
+fn extract_with_ffmpeg(
+    inputs: &[String],
+    output: String,
+    codec: Option<String>,
+    format: Option<String>,
+    split: bool,
+    mut config: ExtractConfig,
+) -> Result<(ExtractSummary, Written), Box<dyn std::error::Error>> {
+    let to_stdout = output == STDOUT_OUTPUT;
+    // There's no extension to guess from, so pick formats that can be written without seeking
+    let format = match format {
+        None if to_stdout => {
+            let default = if codec.is_some() { "matroska" } else { "ogg" };
+            log::info!("Writing {default} to stdout, choose another format with -f");
+            Some(default.to_string())
+        }
+        format => format,
+    };
+    let maybe_format_name = format
+        .map(CString::new)
+        .transpose()
+        .map_err(|_| "The format name contains a NUL byte")?;
+    let output_filename = CString::new(output.clone())
+        .map_err(|_| format!("{output:?} contains a NUL byte, which ffmpeg can't open"))?;
+
+    let oformat = AVOutputFormat::guess_format(
+        maybe_format_name.as_deref(),
+        (!to_stdout).then_some(output_filename.as_c_str()),
+        None,
+    )
+    .ok_or("Couldn't guess output format")?;
+
+    let codec = if let Some(codec) = codec {
+        let codec = CString::new(codec).map_err(|_| "The encoder name contains a NUL byte")?;
+        AVCodec::find_encoder_by_name(&codec).ok_or("Encoder does not exist")?
+    } else {
+        AVCodec::find_encoder(oformat.audio_codec).ok_or_else(|| {
+            format!(
+                "{} has no default audio encoder, choose one with -c",
+                oformat.name().to_string_lossy()
+            )
+        })?
+    };
+    let format_name = oformat.name().to_string_lossy();
+    if config.chapters && !CHAPTER_FORMATS.contains(&format_name.as_ref()) {
+        log::warn!("{format_name} can't carry chapters, leaving them out");
+        config.chapters = false;
+    }
+    if codec.type_ != ffi::AVMEDIA_TYPE_AUDIO {
+        return Err(format!(
+            "{} is not an audio encoder, see --list-formats for the ones that are",
+            codec.name().to_string_lossy()
+        )
+        .into());
+    }
+    // Formats that can't tell whether they take a codec answer with an error, those are left to
+    // fail when the header is written
+    let compliance = ffi::FF_COMPLIANCE_NORMAL as i32;
+    // SAFETY: both the format and the codec are ffmpeg's own, static descriptions
+    if unsafe { ffi::avformat_query_codec(oformat.as_ptr(), codec.id, compliance) } == 0 {
+        let suggestion = match AVCodec::find_encoder(oformat.audio_codec) {
+            Some(default) => format!("try -c {}", default.name().to_string_lossy()),
+            None => "choose another format with -f".to_string(),
+        };
+        return Err(format!(
+            "{format_name} can't hold {} audio, {suggestion}",
+            codec.name().to_string_lossy()
+        )
+        .into());
+    }
+
+    let written = Written::new(
+        &codec.name().to_string_lossy(),
+        &oformat.name().to_string_lossy(),
+    );
+    log::debug!("Encoding with {} into {}", written.codec, written.format);
+    config.codec = Some(codec);
+
+    let demo = open_voice_demo(inputs)?;
+    let demo_name = match Path::new(&inputs[0]).file_stem() {
+        _ if inputs[0] == STDIN_INPUT => "stdin".into(),
+        Some(stem) => stem.to_string_lossy(),
+        None => inputs[0].as_str().into(),
+    };
+    if split {
+        let mut paths = SplitPaths::new(output);
+        let mut create_output = |steam_id: u64, name: &str| {
+            let path = paths.path(steam_id, name);
+            let filename = CString::new(path.into_os_string().into_encoded_bytes())?;
+            let mut fmt_ctx = AVFormatContextOutput::builder()
+                .oformat(&oformat)
+                .filename(&filename)
+                .build()?;
+            let tags = [
+                ("title", name.to_string()),
+                ("comment", format!("{name} ({steam_id}) in {demo_name}")),
+            ];
+            set_container_tags(&mut fmt_ctx, &tags)?;
+            Ok(fmt_ctx)
+        };
+        let summary = extract_owned(demo, &config, Output::Split(&mut create_output))?;
+        Ok((summary, written.to(paths.created)))
+    } else if to_stdout {
+        if SEEKING_FORMATS.contains(&written.format.as_str()) {
+            log::warn!(
+                "{} needs to seek in its output, which isn't possible on stdout",
+                written.format
             );
-            pkt.set_stream_index(player_stream.stream_index as i32);
-            fmt_ctx.write_frame(&mut pkt)?;
         }
+        let mut fmt_ctx = AVFormatContextOutput::builder()
+            .oformat(&oformat)
+            .io_context(AVIOContextContainer::Custom(stdout_io_context()))
+            .build()?;
+        set_container_tags(&mut fmt_ctx, &shared_tags(&demo, &config, &demo_name))?;
+        let summary = extract_owned(demo, &config, Output::Muxed(&mut fmt_ctx))?;
+        Ok((summary, written.to(vec![output.into()])))
+    } else {
+        let mut fmt_ctx = AVFormatContextOutput::builder()
+            .oformat(&oformat)
+            .filename(&output_filename)
+            .build()?;
+        set_container_tags(&mut fmt_ctx, &shared_tags(&demo, &config, &demo_name))?;
+        let summary = extract_owned(demo, &config, Output::Muxed(&mut fmt_ctx))?;
+        Ok((summary, written.to(vec![output.into()])))
     }
+}
 
-    fmt_ctx.write_trailer()?;
+/// Tags of a container shared by every player: the demo it came from and who is speaking in it
+#[cfg(feature = "ffmpeg")]
+fn shared_tags(
+    demo: &Demo,
+    config: &ExtractConfig,
+    demo_name: &str,
+) -> [(&'static str, String); 2] {
+    let names = resolve_names(demo);
+    let mut steam_ids: Vec<u64> = scan_players(demo)
+        .into_keys()
+        .filter(|&steam_id| config.players.allows(steam_id))
+        .collect();
+    steam_ids.sort();
+    let speakers: Vec<String> = steam_ids
+        .iter()
+        .map(|steam_id| match names.get(steam_id) {
+            Some(name) => format!("{name} ({steam_id})"),
+            None => steam_id.to_string(),
+        })
+        .collect();
+    [
+        ("title", demo_name.to_string()),
+        ("comment", format!("Voice of {}", speakers.join(", "))),
+    ]
+}
 
+/// Sets metadata tags on a container, along with the tool that wrote it. Has to happen before
+/// its header is written
+#[cfg(feature = "ffmpeg")]
+fn set_container_tags(
+    fmt_ctx: &mut AVFormatContextOutput,
+    tags: &[(&str, String)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    // libavformat puts its own version in "encoder"
+    let tool = ("encoded_by", ENCODING_TOOL.to_string());
+    for (key, value) in tags.iter().chain([&tool]) {
+        let (key, value) = (CString::new(*key)?, CString::new(value.as_str())?);
+        // SAFETY: the context is valid, and its metadata dictionary may be null or owned by it
+        let ret = unsafe {
+            ffi::av_dict_set(
+                &mut (*fmt_ctx.as_mut_ptr()).metadata,
+                key.as_ptr(),
+                value.as_ptr(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(format!("Couldn't set the {key:?} tag of the output").into());
+        }
+    }
     Ok(())
 }
+
+/// Hands everything ffmpeg muxes straight to stdout, without any way to seek back
+#[cfg(feature = "ffmpeg")]
+fn stdout_io_context() -> AVIOContextCustom {
+    let write_packet: WritePacketCallback = Box::new(|_, data| {
+        let mut stdout = std::io::stdout().lock();
+        match stdout.write_all(data).and_then(|()| stdout.flush()) {
+            Ok(()) => data.len() as i32,
+            Err(_) => ffi::AVERROR_EXTERNAL,
+        }
+    });
+    AVIOContextCustom::alloc_context(
+        AVMem::new(64 * 1024),
+        true,
+        Vec::new(),
+        None,
+        Some(write_packet),
+        None,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steam_ids_convert_from_steam_x_y_z() {
+        assert_eq!(parse_steam_id("STEAM_0:1:2"), Ok(76561197960265733));
+        assert_eq!(parse_steam_id("STEAM_1:0:12345"), Ok(76561197960290418));
+        assert_eq!(
+            parse_steam_id("STEAM_0:1:2147483647"),
+            Ok(76561202255233023)
+        );
+        assert_eq!(parse_steam_id("76561197960265733"), Ok(76561197960265733));
+    }
+
+    #[test]
+    fn account_numbers_past_31_bits_are_rejected() {
+        assert!(parse_steam_id("STEAM_0:0:2147483648").is_err());
+    }
+
+    #[test]
+    fn malformed_steam_ids_are_rejected() {
+        for steam_id in [
+            "STEAM_0:1",
+            "STEAM_0:1:2:3",
+            "STEAM_0:2:2",
+            "STEAM_2:0:2",
+            "STEAM_0:0:x",
+        ] {
+            assert!(parse_steam_id(steam_id).is_err(), "{steam_id}");
+        }
+        assert!(parse_steam_id("player").is_err());
+    }
+
+    #[test]
+    fn bitrates_take_k_and_m_suffixes() {
+        assert_eq!(parse_bitrate("128000"), Ok(128000));
+        assert_eq!(parse_bitrate("64k"), Ok(64000));
+        assert_eq!(parse_bitrate("1.5M"), Ok(1500000));
+        assert_eq!(parse_bitrate("0.5k"), Ok(500));
+    }
+
+    #[test]
+    fn bad_bitrates_are_rejected() {
+        for bitrate in ["64x", "64kb", "k", "", "0", "-64k", "3000M"] {
+            assert!(parse_bitrate(bitrate).is_err(), "{bitrate}");
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::io::{self, Write};
+
+/// Ogg Opus granule positions always count samples at 48kHz, whatever the input rate
+pub const GRANULE_RATE: u64 = 48_000;
+/// Pages are closed once they hold about this many samples, so seeking stays cheap without
+/// spending a whole page header on every packet
+const PAGE_DURATION: u64 = GRANULE_RATE;
+
+const FLAG_BEGINNING_OF_STREAM: u8 = 0x02;
+const FLAG_END_OF_STREAM: u8 = 0x04;
+
+/// CRC-32 of Ogg pages: polynomial 0x04c11db7, not reflected, no initial or final xor
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+/// Writes mono opus packets into an Ogg Opus stream (RFC 7845) as they are, without decoding or
+/// encoding anything
+pub struct OggOpusWriter<W: Write> {
+    writer: W,
+    serial: u32,
+    sequence: u32,
+    /// Lacing values and data of the packets on the page being filled
+    segments: Vec<u8>,
+    body: Vec<u8>,
+    /// Samples written so far, at [`GRANULE_RATE`]
+    granule: u64,
+    page_start: u64,
+}
+
+impl<W: Write> OggOpusWriter<W> {
+    /// Writes the identification and comment headers. `input_sample_rate` is what the voice was
+    /// recorded at, which players may use as their output rate
+    pub fn new(writer: W, serial: u32, input_sample_rate: u32, title: &str) -> io::Result<Self> {
+        let mut ogg = Self {
+            writer,
+            serial,
+            sequence: 0,
+            segments: Vec::new(),
+            body: Vec::new(),
+            granule: 0,
+            page_start: 0,
+        };
+
+        let mut head = b"OpusHead".to_vec();
+        head.push(1); // version
+        head.push(1); // channels
+        // No pre-skip, the delay of the sender's encoder isn't known and the stream should stay
+        // lined up with the demo
+        head.extend_from_slice(&0u16.to_le_bytes());
+        head.extend_from_slice(&input_sample_rate.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+        head.push(0); // channel mapping family: mono or stereo without a mapping table
+        ogg.add_packet(&head);
+        ogg.flush_page(FLAG_BEGINNING_OF_STREAM)?;
+
+        let vendor = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+        let comment = format!("TITLE={title}");
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&1u32.to_le_bytes());
+        tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        tags.extend_from_slice(comment.as_bytes());
+        ogg.add_packet(&tags);
+        ogg.flush_page(0)?;
+
+        Ok(ogg)
+    }
+
+    /// The granule position of what was written so far
+    pub fn position(&self) -> u64 {
+        self.granule
+    }
+
+    /// Appends an opus packet lasting `samples` samples at [`GRANULE_RATE`]
+    pub fn write_packet(&mut self, packet: &[u8], samples: u64) -> io::Result<()> {
+        if self.segments.len() + packet.len() / 255 + 1 > 255 {
+            self.flush_page(0)?;
+        }
+        self.add_packet(packet);
+        self.granule += samples;
+        if self.granule - self.page_start >= PAGE_DURATION {
+            self.flush_page(0)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the last page, marking the end of the stream
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_page(FLAG_END_OF_STREAM)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn add_packet(&mut self, packet: &[u8]) {
+        // A packet is split into 255 byte segments, ended by a shorter one (which may be empty)
+        self.segments
+            .extend(std::iter::repeat_n(255, packet.len() / 255));
+        self.segments.push((packet.len() % 255) as u8);
+        self.body.extend_from_slice(packet);
+    }
+
+    fn flush_page(&mut self, flags: u8) -> io::Result<()> {
+        if self.segments.is_empty() && flags & FLAG_END_OF_STREAM == 0 {
+            return Ok(());
+        }
+        let mut page = Vec::with_capacity(27 + self.segments.len() + self.body.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0); // version
+        page.push(flags);
+        page.extend_from_slice(&self.granule.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]); // CRC, filled in once the page is complete
+        page.push(self.segments.len() as u8);
+        page.append(&mut self.segments);
+        page.append(&mut self.body);
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        self.writer.write_all(&page)?;
+        self.sequence += 1;
+        self.page_start = self.granule;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits a stream back into its pages
+    fn pages(mut stream: &[u8]) -> Vec<&[u8]> {
+        let mut pages = Vec::new();
+        while !stream.is_empty() {
+            assert_eq!(&stream[..4], b"OggS");
+            let segments = &stream[27..27 + stream[26] as usize];
+            let len = 27 + segments.len() + segments.iter().map(|&s| s as usize).sum::<usize>();
+            let (page, rest) = stream.split_at(len);
+            pages.push(page);
+            stream = rest;
+        }
+        pages
+    }
+
+    #[test]
+    fn crc_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0x89a1_897f);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn pages_carry_their_crc() {
+        let mut ogg = OggOpusWriter::new(Vec::new(), 1, 24_000, "player").unwrap();
+        ogg.write_packet(&[0xfc, 0xff, 0xfe], 960).unwrap();
+        let stream = ogg.finish().unwrap();
+        for page in pages(&stream) {
+            let mut unsigned = page.to_vec();
+            unsigned[22..26].fill(0);
+            assert_eq!(page[22..26], crc32(&unsigned).to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn packet_of_whole_segments_ends_in_an_empty_one() {
+        let mut ogg = OggOpusWriter::new(Vec::new(), 1, 24_000, "player").unwrap();
+        ogg.write_packet(&[0xab; 255], 960).unwrap();
+        let stream = ogg.finish().unwrap();
+        let pages = pages(&stream);
+        let page = pages.last().unwrap();
+        assert_eq!(page[26], 2);
+        assert_eq!(page[27..29], [255, 0]);
+        assert_eq!(page[29..], [0xab; 255]);
+        assert_eq!(page[6..14], 960u64.to_le_bytes());
+    }
+}
@@ -0,0 +1,54 @@
+/// Reports how far into the demo playback is as a percentage, redrawn in place on stderr.
+///
+/// Anything else written to stderr while it is shown has to go through
+/// [`Progress::interrupt`] first, so it starts on a line of its own.
+pub struct Progress {
+    enabled: bool,
+    start: f32,
+    duration: f32,
+    percent: Option<u32>,
+}
+
+impl Progress {
+    /// Playback runs from `start` to `end` in demo time
+    pub fn new(enabled: bool, start: f32, end: f32) -> Self {
+        Self {
+            enabled,
+            start,
+            duration: end - start,
+            percent: None,
+        }
+    }
+
+    /// Redraws the percentage if it changed since playback reached `time`
+    pub fn update(&mut self, time: f32) {
+        if !self.enabled {
+            return;
+        }
+        let percent = if self.duration > 0.0 {
+            ((time - self.start) / self.duration * 100.0).clamp(0.0, 100.0) as u32
+        } else {
+            100
+        };
+        if self.percent != Some(percent) {
+            eprint!("\rExtracting: {percent:3}%");
+            self.percent = Some(percent);
+        }
+    }
+
+    /// Ends the line the percentage is drawn on, if there is one. It is redrawn on the next line
+    /// by the next update
+    pub fn interrupt(&mut self) {
+        if self.percent.take().is_some() {
+            eprintln!();
+        }
+    }
+
+    /// Draws the percentage one last time as done and ends its line
+    pub fn finish(&mut self) {
+        if self.enabled {
+            self.update(self.start + self.duration);
+            self.interrupt();
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::ops::Range;
+
+use dem::types::Demo;
+use steam_audio_codec::Packet;
+
+use crate::decoder::{DecoderStats, MAX_PLC_FRAMES, OpusFrame, decoding_rate, opus_frames};
+use crate::discover::{parse_voice_data, playback_frames, voice_messages};
+use crate::ogg::{GRANULE_RATE, OggOpusWriter};
+use crate::{
+    ExtractConfig, ExtractSummary, PlayerSummary, WavFactory, check_voice_data, resolve_names,
+    scan_players, voice_format,
+};
+
+/// A 20ms CELT frame of digital silence
+const SILENCE_FRAME: [u8; 3] = [0xf8, 0xff, 0xfe];
+const SILENCE_FRAME_SAMPLES: u64 = GRANULE_RATE / 50;
+
+/// The Ogg Opus file of a single player, along with what [`crate::decoder::SteamVoiceDecoder`]
+/// would track while decoding their voice
+struct RawOpusStream {
+    writer: OggOpusWriter<BufWriter<File>>,
+    name: String,
+    /// Sequence number the next frame should have, `None` until the first frame
+    seq: Option<u16>,
+    /// TOC byte of the last frame, concealed frames take their duration from it
+    last_toc: Option<u8>,
+    /// Like [`SteamVoiceDecoder::voice_rate`](crate::decoder::SteamVoiceDecoder)
+    voice_rate: Option<u16>,
+    max_plc_frames: usize,
+    /// Ranges the player was talking in, at [`GRANULE_RATE`]
+    speech: Vec<Range<u64>>,
+    stats: DecoderStats,
+}
+
+impl RawOpusStream {
+    /// Fills the stream with silence up to `position`, to the nearest frame
+    fn pad_to(&mut self, position: u64) -> io::Result<()> {
+        while self.writer.position() + SILENCE_FRAME_SAMPLES <= position {
+            self.writer
+                .write_packet(&SILENCE_FRAME, SILENCE_FRAME_SAMPLES)?;
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let samples = opus::packet::get_nb_samples(frame, GRANULE_RATE as u32)?;
+        self.writer.write_packet(frame, samples as u64)?;
+        Ok(())
+    }
+
+    /// Copies a single opus frame into the stream, concealing the frames lost before it the way
+    /// decoding does. Frames from before the start of the time range only keep the sequence
+    /// numbers up to date
+    fn add_frame(
+        &mut self,
+        frame: OpusFrame,
+        before_start: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (seq, data) = match frame {
+            OpusFrame::Reset => {
+                self.seq = Some(0);
+                self.stats.resets += 1;
+                return Ok(());
+            }
+            OpusFrame::Frame { seq, data } => (seq, data),
+        };
+        if self.seq.is_some_and(|expected| seq < expected) {
+            self.stats.resets += 1;
+        }
+        if let Some(expected) = self.seq
+            && seq > expected
+        {
+            let lost = (seq - expected) as usize;
+            self.stats.lost_packets += lost;
+            if let Some(toc) = self.last_toc
+                && !before_start
+            {
+                // A frame without any data is decoded as a lost one, lasting as long as the
+                // frames its TOC byte describes
+                let concealed = lost.min(self.max_plc_frames);
+                for _ in 0..concealed {
+                    self.write_frame(&[toc & 0xfc])?;
+                }
+                self.stats.concealed_frames += concealed;
+            }
+        }
+        self.seq = Some(seq.wrapping_add(1));
+
+        if before_start || data.is_empty() {
+            return Ok(());
+        }
+        self.write_frame(data)?;
+        self.last_toc = Some(data[0]);
+        self.stats.packets += 1;
+        Ok(())
+    }
+}
+
+/// Copies the opus voice data of every player into their own Ogg Opus file as it was sent,
+/// without decoding or encoding anything.
+///
+/// Like the other outputs, every file starts at the start of the demo (or
+/// [`ExtractConfig::start`]) and is padded with silence while the player isn't talking, so the
+/// files line up when played back together, though only to the nearest 20ms. Lost frames are
+/// concealed by the player's decoder, up to as many as decoding would conceal. Mixing,
+/// normalizing, gain, fades, filtering, silence trimming, offsets and dual mono all need decoded
+/// audio, so they aren't supported.
+pub fn extract_raw_opus(
+    demo: &Demo,
+    config: &ExtractConfig,
+    create_file: &mut WavFactory,
+) -> Result<ExtractSummary, Box<dyn std::error::Error>> {
+    if config.mix
+        || config.normalize.is_some()
+        || config.trim_silence.is_some()
+        || config.gain != 0.0
+        || !config.player_gains.is_empty()
+        || config.offset != 0.0
+        || !config.player_offsets.is_empty()
+        || config.fade_in > 0.0
+        || config.fade_out > 0.0
+        || config.highpass.is_some()
+        || config.dual_mono
+    {
+        return Err(
+            "Raw opus can't be mixed, normalized, amplified, faded, filtered, trimmed, \
+            shifted or written in stereo"
+                .into(),
+        );
+    }
+    config.validate()?;
+    check_voice_data(demo)?;
+    let voice_rate = voice_format(demo).sample_rate;
+    // Silence is counted in samples of the decoded voice until the voice data announces its rate
+    let default_silence_rate = decoding_rate(voice_rate) as u64;
+
+    let names = resolve_names(demo);
+    let mut streams = HashMap::new();
+    let mut steam_ids: Vec<u64> = scan_players(demo).into_keys().collect();
+    steam_ids.sort();
+    for steam_id in steam_ids {
+        if !config.players.allows(steam_id) {
+            continue;
+        }
+        let name = names
+            .get(&steam_id)
+            .cloned()
+            .unwrap_or_else(|| steam_id.to_string());
+        let file = BufWriter::new(create_file(steam_id, &name)?);
+        let writer = OggOpusWriter::new(file, steam_id as u32, voice_rate as u32, &name)?;
+        log::debug!("Created the raw opus stream of {name} ({steam_id})");
+        let stream = RawOpusStream {
+            writer,
+            name,
+            seq: None,
+            last_toc: None,
+            voice_rate: None,
+            max_plc_frames: config.max_plc_frames.min(MAX_PLC_FRAMES),
+            speech: Vec::new(),
+            stats: DecoderStats::default(),
+        };
+        streams.insert(steam_id, stream);
+    }
+    let start = config.start.unwrap_or(0.0);
+    let seconds_to_position = |seconds: f32| (seconds.max(0.0) as f64 * GRANULE_RATE as f64) as u64;
+    let jitter_buffer = seconds_to_position(config.jitter_buffer);
+    let mut now = 0;
+    let mut unparsed = 0;
+    let frames = playback_frames(demo).filter(|frame| !config.after_end(frame.time));
+    for demo_frame in frames {
+        let before_start = config.before_start(demo_frame.time);
+        if !before_start {
+            now = now.max(seconds_to_position(demo_frame.time - start));
+        }
+        for svc_voice_data in voice_messages(demo_frame) {
+            let Ok(steam_voice_data) = parse_voice_data(&svc_voice_data.data) else {
+                unparsed += 1;
+                if unparsed == 1 {
+                    log::warn!("Failed to parse svc_voice_data as steam voice data!");
+                }
+                continue;
+            };
+            let Some(stream) = streams.get_mut(&steam_voice_data.steam_id) else {
+                continue;
+            };
+
+            if !before_start && now >= stream.writer.position() {
+                // The player's buffer ran dry, so like during playback, this speech only starts
+                // once the jitter buffer has filled again
+                stream.pad_to(now + jitter_buffer)?;
+                let position = stream.writer.position();
+                stream.speech.push(position..position);
+            }
+            for packet in steam_voice_data.packets() {
+                let packet = match packet {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        log::warn!("Invalid voice packet: {e}");
+                        break;
+                    }
+                };
+                match packet {
+                    // Like when decoding, a new rate means a new encoder with its own sequence
+                    Packet::SampleRate(rate) => {
+                        if stream
+                            .voice_rate
+                            .is_some_and(|voice_rate| voice_rate != rate)
+                        {
+                            stream.seq = None;
+                            stream.stats.resets += 1;
+                        }
+                        stream.voice_rate = Some(rate);
+                    }
+                    Packet::Silence(_) if before_start => {}
+                    Packet::Silence(samples) => {
+                        let silence_rate = match stream.voice_rate {
+                            Some(rate) if rate != 0 => rate as u64,
+                            _ => default_silence_rate,
+                        };
+                        let samples = samples as u64 * GRANULE_RATE / silence_rate;
+                        stream.pad_to(stream.writer.position() + samples)?;
+                    }
+                    Packet::OpusPlc(opus) => match opus_frames(opus.as_slice()) {
+                        Ok(frames) => {
+                            for frame in frames {
+                                stream.add_frame(frame, before_start)?;
+                            }
+                        }
+                        Err(e) => log::warn!("Decoder error: {e}"),
+                    },
+                }
+            }
+            if let Some(interval) = stream.speech.last_mut() {
+                interval.end = stream.writer.position();
+            }
+        }
+    }
+
+    if unparsed > 1 {
+        log::warn!("Skipped {unparsed} svc_voice_data messages that weren't steam voice data");
+    }
+
+    let position_to_seconds = |position: u64| position as f64 / GRANULE_RATE as f64;
+    let mut players = HashMap::new();
+    for (steam_id, mut stream) in streams {
+        stream.pad_to(now)?;
+        stream.writer.finish()?;
+        let speech = stream
+            .speech
+            .iter()
+            .map(|interval| position_to_seconds(interval.start)..position_to_seconds(interval.end))
+            .collect();
+        let summary = PlayerSummary {
+            name: stream.name,
+            speech,
+            decoded_samples: 0,
+            peak: 0.0,
+            rms: 0.0,
+            decoder: stream.stats,
+            samples: Vec::new(),
+        };
+        players.insert(steam_id, summary);
+    }
+    Ok(ExtractSummary {
+        sample_rate: GRANULE_RATE as i32,
+        players,
+    })
+}
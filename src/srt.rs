@@ -0,0 +1,133 @@
+use std::io::{self, Write};
+
+use crate::ExtractSummary;
+
+/// A single subtitle, times in seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrtCue {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+    /// Name of the player talking
+    pub speaker: String,
+}
+
+/// Turns the speech of every player into one `[name]` cue per time they were talking. Pauses
+/// shorter than `merge_gap` seconds don't split a cue, and players talking over each other get
+/// overlapping cues.
+pub fn speech_cues(summary: &ExtractSummary, merge_gap: f64) -> Vec<SrtCue> {
+    let mut cues = Vec::new();
+    for player in summary.players.values() {
+        let mut current: Option<SrtCue> = None;
+        for interval in &player.speech {
+            match current.as_mut() {
+                Some(cue) if interval.start - cue.end < merge_gap => cue.end = interval.end,
+                _ => {
+                    cues.extend(current.take());
+                    current = Some(SrtCue {
+                        start: interval.start,
+                        end: interval.end,
+                        text: format!("[{}]", player.name),
+                        speaker: player.name.clone(),
+                    });
+                }
+            }
+        }
+        cues.extend(current);
+    }
+    cues.sort_by(|a, b| {
+        a.start
+            .total_cmp(&b.start)
+            .then_with(|| a.text.cmp(&b.text))
+    });
+    cues
+}
+
+/// Writes `cues` in the SubRip format, numbered in the order given
+pub fn write_srt(mut writer: impl Write, cues: &[SrtCue]) -> io::Result<()> {
+    for (index, cue) in cues.iter().enumerate() {
+        writeln!(writer, "{}", index + 1)?;
+        writeln!(
+            writer,
+            "{} --> {}",
+            timestamp(cue.start, ','),
+            timestamp(cue.end, ',')
+        )?;
+        writeln!(writer, "{}", cue.text)?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Writes `cues` as WebVTT, for HTML5 `<track>` elements. Every cue is a voice span of its
+/// speaker, so players can style or label them
+pub fn write_vtt(mut writer: impl Write, cues: &[SrtCue]) -> io::Result<()> {
+    let escape = |text: &str| {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+    writeln!(writer, "WEBVTT")?;
+    writeln!(writer)?;
+    for cue in cues {
+        writeln!(
+            writer,
+            "{} --> {}",
+            timestamp(cue.start, '.'),
+            timestamp(cue.end, '.')
+        )?;
+        writeln!(writer, "<v {}>{}", escape(&cue.speaker), escape(&cue.text))?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Formats `seconds` as HH:MM:SS, then `separator` and the milliseconds
+fn timestamp(seconds: f64, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{separator}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srt_timestamps_round_to_the_millisecond() {
+        assert_eq!(timestamp(0.0, ','), "00:00:00,000");
+        assert_eq!(timestamp(3725.5, ','), "01:02:05,500");
+        assert_eq!(timestamp(2.0625, ','), "00:00:02,063");
+        // Rounding up carries into the seconds, minutes and hours
+        assert_eq!(timestamp(59.99975, ','), "00:01:00,000");
+        assert_eq!(timestamp(3599.9999, ','), "01:00:00,000");
+        assert_eq!(timestamp(-1.0, ','), "00:00:00,000");
+    }
+
+    #[test]
+    fn vtt_timestamps_use_a_dot() {
+        assert_eq!(timestamp(3725.5, '.'), "01:02:05.500");
+        assert_eq!(timestamp(59.99975, '.'), "00:01:00.000");
+    }
+
+    #[test]
+    fn vtt_cues_are_escaped_voice_spans() {
+        let cues = [SrtCue {
+            start: 2.0625,
+            end: 3599.9999,
+            text: "[a<b>]".into(),
+            speaker: "a<b>".into(),
+        }];
+        let mut vtt = Vec::new();
+        write_vtt(&mut vtt, &cues).unwrap();
+        assert_eq!(
+            String::from_utf8(vtt).unwrap(),
+            "WEBVTT\n\n00:00:02.063 --> 01:00:00.000\n<v a&lt;b&gt;>[a&lt;b&gt;]\n\n"
+        );
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::ops::Range;
+
+use crate::decoder::SteamVoiceDecoder;
+use crate::dsp::HighPass;
+#[cfg(feature = "ffmpeg")]
+use crate::encoder::Encoder;
+use crate::trim::SilenceTrimmer;
+use crate::wav::WavWriter;
+use crate::{ExtractConfig, SAMPLE_FMT_FLT, SAMPLE_FMT_S16, bytes_per_sample};
+#[cfg(feature = "ffmpeg")]
+use rsmpeg::avformat::AVFormatContextOutput;
+
+/// Where the samples of a player end up
+pub enum Sink {
+    /// Encoded with ffmpeg
+    #[cfg(feature = "ffmpeg")]
+    Encoder(Encoder),
+    /// Written natively into the player's own WAV file
+    Wav(WavWriter<BufWriter<File>>),
+    /// Interleaved natively into this channel of a shared WAV file
+    WavChannel(usize),
+    /// Summed with every other player into a single mixed down track, with this gain in each of
+    /// the mix's channels
+    Mix(Vec<f32>),
+    /// Kept in memory and handed back once extraction is done
+    Samples(Vec<u8>),
+    /// Dropped, see [`crate::Output::Discard`]
+    Discard,
+}
+
+impl Sink {
+    /// Writes `samples` into the sink's own output. The mix and the channels of a shared file are
+    /// only written once every player added to them, so they have to be filled in instead
+    pub fn write(
+        &mut self,
+        samples: &[u8],
+        #[cfg(feature = "ffmpeg")] muxer: Option<&mut AVFormatContextOutput>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            #[cfg(feature = "ffmpeg")]
+            Sink::Encoder(encoder) => encoder.encode(samples, muxer)?,
+            Sink::Wav(writer) => writer.write_samples(samples)?,
+            Sink::Samples(buffer) => buffer.extend_from_slice(samples),
+            Sink::Discard => {}
+            Sink::WavChannel(_) | Sink::Mix(_) => unreachable!("Shared output written on its own"),
+        }
+        Ok(())
+    }
+
+    /// Flushes whatever the sink's own output still holds once extraction is done
+    pub fn finish(
+        &mut self,
+        #[cfg(feature = "ffmpeg")] muxer: Option<&mut AVFormatContextOutput>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            #[cfg(feature = "ffmpeg")]
+            Sink::Encoder(encoder) => encoder.finish(muxer)?,
+            Sink::Wav(writer) => writer.finish()?,
+            Sink::WavChannel(_) | Sink::Mix(_) | Sink::Samples(_) | Sink::Discard => {}
+        }
+        Ok(())
+    }
+}
+
+/// The single track every player is summed into when mixing down
+pub struct Mixer {
+    /// Sample format every mixed down player has to be decoded to
+    pub decoder_format: i32,
+    /// Interleaved samples of the current demo frame, handed to the sink once every player was
+    /// added
+    pub samples: Vec<u8>,
+    pub sink: Sink,
+}
+
+/// Adds the mono `samples` onto the interleaved `mixed`, scaled by one gain per channel, clamping
+/// instead of overflowing. `mixed` is extended with silence if it is shorter.
+pub fn mix_samples(mixed: &mut Vec<u8>, samples: &[u8], sample_format: i32, gains: &[f32]) {
+    let bytes_per_sample = bytes_per_sample(sample_format);
+    let len = samples.len() * gains.len();
+    if mixed.len() < len {
+        mixed.resize(len, 0);
+    }
+    let frames = mixed.chunks_exact_mut(bytes_per_sample * gains.len());
+    for (frame, src) in frames.zip(samples.chunks_exact(bytes_per_sample)) {
+        for (dst, gain) in frame.chunks_exact_mut(bytes_per_sample).zip(gains) {
+            match sample_format {
+                SAMPLE_FMT_S16 => {
+                    let sum = i16::from_ne_bytes([dst[0], dst[1]]) as f32
+                        + i16::from_ne_bytes([src[0], src[1]]) as f32 * gain;
+                    let sum = sum.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                    dst.copy_from_slice(&sum.to_ne_bytes());
+                }
+                SAMPLE_FMT_FLT => {
+                    let sum = f32::from_ne_bytes(dst.try_into().unwrap())
+                        + f32::from_ne_bytes(src.try_into().unwrap()) * gain;
+                    dst.copy_from_slice(&sum.clamp(-1.0, 1.0).to_ne_bytes());
+                }
+                _ => panic!("mixing samples in a sample format that we didn't account for!"),
+            }
+        }
+    }
+}
+
+pub struct PlayerStream {
+    pub decoded_samples: VecDeque<u8>,
+    pub time_pad: f32,
+    /// Seconds of voice buffered before playback starts, see
+    /// [`ExtractConfig::jitter_buffer`]
+    pub jitter_buffer: f32,
+    pub playing: bool,
+    pub last_demo_pts: i64,
+    pub decoder: SteamVoiceDecoder,
+    pub sample_format: i32,
+    pub bytes_per_sample: usize,
+    /// Applied to every sample returned by [`PlayerStream::advance`]
+    pub gain: f32,
+    /// See [`crate::PlayerSummary::name`]
+    pub name: String,
+    /// Output pts ranges the player's voice was playing in, before any silence is trimmed
+    pub speech: Vec<Range<i64>>,
+    /// How many samples were decoded for the player in total
+    pub decoded_sample_count: usize,
+    /// Largest absolute sample decoded, as a fraction of full scale
+    pub peak: f32,
+    /// Sum of the squares of every decoded sample, as fractions of full scale
+    pub sum_of_squares: f64,
+    /// Filters the samples returned by [`PlayerStream::advance`], see [`ExtractConfig::highpass`]
+    pub highpass: Option<HighPass>,
+    /// Samples speech fades in over once it starts playing, see [`ExtractConfig::fade_in`]
+    pub fade_in: usize,
+    /// Samples speech fades out over before the buffer runs dry, see [`ExtractConfig::fade_out`]
+    pub fade_out: usize,
+    /// Samples played since the player started talking
+    pub played: usize,
+    /// Samples the player's voice is shifted by relative to demo time, see
+    /// [`ExtractConfig::player_offsets`]
+    pub offset: i64,
+    /// Silence the player's voice is delayed by, which every sample passes through in order.
+    /// Negative offsets skip the start of every player's output instead, with everyone else
+    /// delayed to make up for it
+    pub delay: VecDeque<u8>,
+    /// Samples still to be dropped off the start of the output. The same for every player, so
+    /// they keep producing as many samples as each other
+    pub skip: usize,
+    /// Takes the silence out of the samples returned by [`PlayerStream::advance`]
+    pub trimmer: Option<SilenceTrimmer>,
+    /// Channels the samples returned by [`PlayerStream::advance`] are copied into, see
+    /// [`ExtractConfig::dual_mono`]
+    pub channels: usize,
+    /// Samples held back to be encoded all at once after playback, see
+    /// [`crate::ExtractConfig::jobs`]
+    #[cfg(feature = "ffmpeg")]
+    pub timeline: Vec<u8>,
+    pub sink: Sink,
+}
+
+impl PlayerStream {
+    pub fn new(
+        decoder_format: i32,
+        sample_rate: i32,
+        config: &ExtractConfig,
+        name: String,
+        sink: Sink,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let seconds_to_samples = |seconds: f32| (seconds * sample_rate as f32).round() as usize;
+        // Only the player's own file is written in stereo, mixed and shared outputs take mono
+        #[cfg(feature = "ffmpeg")]
+        let own_file = matches!(sink, Sink::Encoder(_) | Sink::Wav(_));
+        #[cfg(not(feature = "ffmpeg"))]
+        let own_file = matches!(sink, Sink::Wav(_));
+        Ok(Self {
+            decoded_samples: VecDeque::new(),
+            time_pad: config.jitter_buffer,
+            jitter_buffer: config.jitter_buffer,
+            playing: false,
+            last_demo_pts: 0,
+            decoder: SteamVoiceDecoder::new(decoder_format, sample_rate)?
+                .with_max_plc_frames(config.max_plc_frames),
+            sample_format: decoder_format,
+            bytes_per_sample: bytes_per_sample(decoder_format),
+            gain: 1.0,
+            name,
+            speech: Vec::new(),
+            decoded_sample_count: 0,
+            peak: 0.0,
+            sum_of_squares: 0.0,
+            fade_in: seconds_to_samples(config.fade_in),
+            fade_out: seconds_to_samples(config.fade_out),
+            played: 0,
+            highpass: config
+                .highpass
+                .map(|cutoff| HighPass::new(cutoff, sample_rate)),
+            offset: 0,
+            delay: VecDeque::new(),
+            skip: 0,
+            trimmer: None,
+            channels: if config.dual_mono && own_file { 2 } else { 1 },
+            #[cfg(feature = "ffmpeg")]
+            timeline: Vec::new(),
+            sink,
+        })
+    }
+
+    pub fn append_samples(&mut self, samples: &[u8]) {
+        if self.buffered_samples() == 0 {
+            self.time_pad = self.jitter_buffer;
+        }
+        self.decoded_samples.extend(samples);
+        self.decoded_sample_count += samples.len() / self.bytes_per_sample;
+        for sample in samples.chunks_exact(self.bytes_per_sample) {
+            let level = match self.sample_format {
+                SAMPLE_FMT_S16 => i16::from_ne_bytes([sample[0], sample[1]]) as f32 / 32768.0,
+                SAMPLE_FMT_FLT => f32::from_ne_bytes(sample.try_into().unwrap()),
+                _ => panic!("measuring samples in a sample format that we didn't account for!"),
+            };
+            self.peak = self.peak.max(level.abs());
+            self.sum_of_squares += (level as f64).powi(2);
+        }
+    }
+
+    /// Takes `sample_count` samples off the front of the buffer, padded with silence if fewer
+    /// are buffered
+    pub fn consume_samples(&mut self, sample_count: usize) -> Vec<u8> {
+        let bytes = sample_count * self.bytes_per_sample;
+        let mut samples = Vec::with_capacity(bytes);
+        samples.extend(
+            self.decoded_samples
+                .drain(..bytes.min(self.decoded_samples.len())),
+        );
+        samples.resize(bytes, 0);
+        samples
+    }
+
+    pub fn buffered_samples(&self) -> usize {
+        self.decoded_samples.len()
+    }
+
+    /// Fades in the start of the speech in `samples`, just taken off the buffer, and fades out
+    /// whatever is about to empty the buffer. A buffer that runs this low mid speech dips in
+    /// volume too, which with the jitter buffer only happens when the voice data stutters anyway.
+    fn fade(&mut self, samples: &mut [u8]) {
+        let remaining = self.buffered_samples() / self.bytes_per_sample;
+        let count = samples.len() / self.bytes_per_sample;
+        let played = self.played;
+        self.played += count;
+        if self.fade_in == 0 && self.fade_out == 0 {
+            return;
+        }
+        let ramp = |position: usize, length: usize| {
+            if length == 0 {
+                1.0
+            } else {
+                ((position + 1) as f32 / length as f32).min(1.0)
+            }
+        };
+        for (index, sample) in samples.chunks_exact_mut(self.bytes_per_sample).enumerate() {
+            let gain = ramp(played + index, self.fade_in)
+                * ramp(remaining + count - index - 1, self.fade_out);
+            if gain == 1.0 {
+                continue;
+            }
+            match self.sample_format {
+                SAMPLE_FMT_S16 => {
+                    let faded = i16::from_ne_bytes([sample[0], sample[1]]) as f32 * gain;
+                    sample.copy_from_slice(&(faded.round() as i16).to_ne_bytes());
+                }
+                SAMPLE_FMT_FLT => {
+                    let faded = f32::from_ne_bytes(sample.try_into().unwrap()) * gain;
+                    sample.copy_from_slice(&faded.to_ne_bytes());
+                }
+                _ => panic!("fading samples in a sample format that we didn't account for!"),
+            }
+        }
+    }
+
+    /// Passes `samples` through the delay, then drops whatever is still to be skipped
+    fn shift(&mut self, mut samples: Vec<u8>) -> Vec<u8> {
+        if !self.delay.is_empty() {
+            self.delay.extend(&samples);
+            let len = samples.len();
+            samples.clear();
+            samples.extend(self.delay.drain(..len));
+        }
+        let skip = self.skip.min(samples.len() / self.bytes_per_sample);
+        self.skip -= skip;
+        samples.drain(..skip * self.bytes_per_sample);
+        samples
+    }
+
+    /// Ends the player's output once the demo is done with `sample_count` more samples: what is
+    /// still delayed, padded with silence
+    pub fn finish_shift(&mut self, sample_count: usize) -> Vec<u8> {
+        let delayed = std::mem::take(&mut self.delay).into();
+        let mut samples = self.shift(delayed);
+        samples.resize(sample_count * self.bytes_per_sample, 0);
+        let samples = match self.trimmer.as_mut() {
+            Some(trimmer) => trimmer.trim(&samples),
+            None => samples,
+        };
+        self.to_channels(samples)
+    }
+
+    /// Copies mono `samples` into every channel of the player's output
+    pub fn to_channels(&self, samples: Vec<u8>) -> Vec<u8> {
+        if self.channels == 1 {
+            return samples;
+        }
+        let mut interleaved = Vec::with_capacity(samples.len() * self.channels);
+        mix_samples(
+            &mut interleaved,
+            &samples,
+            self.sample_format,
+            &vec![1.0; self.channels],
+        );
+        interleaved
+    }
+
+    /// Advances the player to `demo_frame_time_as_pts`, returning the samples that play in the
+    /// meantime: buffered voice once the jitter buffer has filled, silence otherwise.
+    pub fn advance(&mut self, frametime: f32, demo_frame_time_as_pts: i64) -> Vec<u8> {
+        if !self.playing && self.buffered_samples() != 0 {
+            self.time_pad -= frametime;
+            if self.time_pad <= 0.0 {
+                self.playing = true;
+                self.played = 0;
+                if let Some(highpass) = self.highpass.as_mut() {
+                    highpass.reset();
+                }
+            }
+        }
+
+        // A frame from before the last one, which the frame loop should have held back already,
+        // advances by nothing instead of underflowing into a huge frame
+        let frame_end = demo_frame_time_as_pts.max(self.last_demo_pts);
+        let demo_frame_sample_count = frame_end
+            .checked_sub(self.last_demo_pts)
+            .and_then(|sample_count| usize::try_from(sample_count).ok())
+            .unwrap_or(0);
+        let frame_start = std::mem::replace(&mut self.last_demo_pts, frame_end);
+
+        let samples = if self.playing {
+            let start = (frame_start + self.offset).max(0);
+            let end = (frame_end + self.offset).max(0);
+            match self.speech.last_mut() {
+                Some(interval) if interval.end == start => interval.end = end,
+                _ if start == end => {}
+                _ => self.speech.push(start..end),
+            }
+            let mut samples = self.consume_samples(demo_frame_sample_count);
+            if let Some(highpass) = self.highpass.as_mut() {
+                highpass.process(&mut samples, self.sample_format);
+            }
+            if self.gain != 1.0 {
+                let unscaled = std::mem::take(&mut samples);
+                mix_samples(&mut samples, &unscaled, self.sample_format, &[self.gain]);
+            }
+            self.fade(&mut samples);
+            if self.buffered_samples() == 0 {
+                self.playing = false;
+            }
+            samples
+        } else {
+            vec![0u8; demo_frame_sample_count * self.bytes_per_sample]
+        };
+        let samples = self.shift(samples);
+        let samples = match self.trimmer.as_mut() {
+            Some(trimmer) => trimmer.trim(&samples),
+            None => samples,
+        };
+        self.to_channels(samples)
+    }
+}
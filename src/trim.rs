@@ -0,0 +1,121 @@
+use crate::{SAMPLE_FMT_FLT, TrimSilence, bytes_per_sample};
+
+/// Silence held back by [`SilenceTrimmer`]. Players are padded with digital silence whenever they
+/// aren't talking, so runs of it are only counted, and just the quiet bits of actual voice are
+/// kept around
+enum Quiet {
+    Zeros(usize),
+    Samples(Vec<u8>),
+}
+
+/// Drops the silence around and between the speech of a single player as it is played back.
+///
+/// Silence is only held back until the next sample of speech shows up.
+pub struct SilenceTrimmer {
+    sample_format: i32,
+    bytes_per_sample: usize,
+    /// Samples at or below this fraction of full scale count as silence
+    threshold: f32,
+    /// Silence kept before and after speech, in samples
+    padding: usize,
+    /// Longest gap between speech that is kept as is, in samples
+    max_gap: Option<usize>,
+    started: bool,
+    pending: Vec<Quiet>,
+    pending_silence: usize,
+}
+
+impl SilenceTrimmer {
+    pub fn new(trim: &TrimSilence, sample_rate: i32, sample_format: i32) -> Self {
+        let seconds_to_samples = |seconds: f32| (seconds * sample_rate as f32).round() as usize;
+        Self {
+            sample_format,
+            bytes_per_sample: bytes_per_sample(sample_format),
+            threshold: trim.threshold.map_or(0.0, |db| 10f32.powf(db / 20.0)),
+            padding: seconds_to_samples(trim.padding),
+            max_gap: trim.max_gap.map(seconds_to_samples),
+            started: false,
+            pending: Vec::new(),
+            pending_silence: 0,
+        }
+    }
+
+    /// Returns `samples` with the trimmed silence taken out. Silence at the end is held back until
+    /// either more speech or [`SilenceTrimmer::finish`] decides how much of it is kept
+    pub fn trim(&mut self, samples: &[u8]) -> Vec<u8> {
+        let mut trimmed = Vec::new();
+        for sample in samples.chunks_exact(self.bytes_per_sample) {
+            if self.is_silent(sample) {
+                self.hold(sample);
+                continue;
+            }
+            // Leading silence and overly long gaps are cut down to the silence right before the
+            // speech
+            let kept =
+                if !self.started || self.max_gap.is_some_and(|max| self.pending_silence > max) {
+                    self.pending_silence.saturating_sub(self.padding)..self.pending_silence
+                } else {
+                    0..self.pending_silence
+                };
+            self.release(kept, &mut trimmed);
+            trimmed.extend_from_slice(sample);
+            self.started = true;
+        }
+        trimmed
+    }
+
+    /// Returns the silence kept after the last bit of speech
+    pub fn finish(&mut self) -> Vec<u8> {
+        let kept = if self.started {
+            0..self.pending_silence.min(self.padding)
+        } else {
+            0..0
+        };
+        let mut trimmed = Vec::new();
+        self.release(kept, &mut trimmed);
+        trimmed
+    }
+
+    fn is_silent(&self, sample: &[u8]) -> bool {
+        let level = if self.sample_format == SAMPLE_FMT_FLT {
+            f32::from_ne_bytes(sample.try_into().unwrap()).abs()
+        } else {
+            (i16::from_ne_bytes(sample.try_into().unwrap()) as f32 / 32768.0).abs()
+        };
+        level <= self.threshold
+    }
+
+    fn hold(&mut self, sample: &[u8]) {
+        self.pending_silence += 1;
+        let zero = sample.iter().all(|&byte| byte == 0);
+        match self.pending.last_mut() {
+            Some(Quiet::Zeros(count)) if zero => *count += 1,
+            Some(Quiet::Samples(samples)) if !zero => samples.extend_from_slice(sample),
+            _ if zero => self.pending.push(Quiet::Zeros(1)),
+            _ => self.pending.push(Quiet::Samples(sample.to_vec())),
+        }
+    }
+
+    /// Appends the `kept` samples of the held back silence to `output` and drops the rest
+    fn release(&mut self, kept: std::ops::Range<usize>, output: &mut Vec<u8>) {
+        let mut position = 0;
+        for quiet in self.pending.drain(..) {
+            let length = match &quiet {
+                Quiet::Zeros(count) => *count,
+                Quiet::Samples(samples) => samples.len() / self.bytes_per_sample,
+            };
+            let start = kept.start.clamp(position, position + length) - position;
+            let end = kept.end.clamp(position, position + length) - position;
+            match quiet {
+                Quiet::Zeros(_) => {
+                    output.resize(output.len() + (end - start) * self.bytes_per_sample, 0)
+                }
+                Quiet::Samples(samples) => output.extend_from_slice(
+                    &samples[start * self.bytes_per_sample..end * self.bytes_per_sample],
+                ),
+            }
+            position += length;
+        }
+        self.pending_silence = 0;
+    }
+}
@@ -0,0 +1,157 @@
+use std::borrow::Cow;
+use std::io::{self, Seek, SeekFrom, Write};
+
+const HEADER_LEN: u32 = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+
+/// Writes 16-bit PCM samples into a canonical RIFF/WAVE file.
+///
+/// The header is written up front with empty sizes, which are patched in by [`WavWriter::finish`].
+/// A writer created with [`WavWriter::raw`] writes the bare samples without any header instead.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    channels: u16,
+    bytes_per_sample: usize,
+    header: bool,
+    data_len: u64,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let block_align = channels * (BITS_PER_SAMPLE / 8);
+        let byte_rate = sample_rate * block_align as u32;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // WAVE_FORMAT_PCM
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&byte_rate.to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            channels,
+            bytes_per_sample: BITS_PER_SAMPLE as usize / 8,
+            header: true,
+            data_len: 0,
+        })
+    }
+
+    /// Creates a writer for bare interleaved samples of `bytes_per_sample` bytes each, without
+    /// any header
+    pub fn raw(writer: W, channels: u16, bytes_per_sample: usize) -> Self {
+        Self {
+            writer,
+            channels,
+            bytes_per_sample,
+            header: false,
+            data_len: 0,
+        }
+    }
+
+    /// Appends already interleaved samples, in native byte order
+    pub fn write_samples(&mut self, samples: &[u8]) -> io::Result<()> {
+        self.writer
+            .write_all(&to_le_bytes(samples, self.bytes_per_sample))?;
+        self.data_len += samples.len() as u64;
+        Ok(())
+    }
+
+    /// Interleaves one chunk of samples per channel and appends them. Shorter chunks are padded
+    /// with silence.
+    pub fn write_channels(&mut self, channels: &[Vec<u8>]) -> io::Result<()> {
+        debug_assert_eq!(channels.len(), self.channels as usize);
+        let size = self.bytes_per_sample;
+        let sample_count = channels
+            .iter()
+            .map(|chunk| chunk.len() / size)
+            .max()
+            .unwrap_or(0);
+        let silence = vec![0u8; size];
+        let mut interleaved = Vec::with_capacity(sample_count * channels.len() * size);
+        for sample in 0..sample_count {
+            for chunk in channels {
+                let bytes = chunk.get(sample * size..(sample + 1) * size);
+                interleaved.extend_from_slice(bytes.unwrap_or(&silence));
+            }
+        }
+        self.write_samples(&interleaved)
+    }
+
+    /// Patches the chunk sizes into the header and flushes the writer
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.header {
+            return self.writer.flush();
+        }
+        // Sizes saturate for files past 4GiB, which most readers treat as "until end of file"
+        let data_len = u32::try_from(self.data_len).unwrap_or(u32::MAX);
+        let riff_len = data_len.saturating_add(HEADER_LEN - 8);
+
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&riff_len.to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(HEADER_LEN as u64 - 4))?;
+        self.writer.write_all(&data_len.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+/// Converts samples of `bytes_per_sample` bytes each from the native byte order they are decoded
+/// in to the little-endian WAV and raw PCM are written in
+fn to_le_bytes(samples: &[u8], bytes_per_sample: usize) -> Cow<'_, [u8]> {
+    if cfg!(target_endian = "little") {
+        return Cow::Borrowed(samples);
+    }
+    let samples = samples.chunks_exact(bytes_per_sample);
+    Cow::Owned(match bytes_per_sample {
+        2 => samples
+            .flat_map(|sample| i16::from_ne_bytes([sample[0], sample[1]]).to_le_bytes())
+            .collect(),
+        _ => samples
+            .flat_map(|sample| {
+                u32::from_ne_bytes([sample[0], sample[1], sample[2], sample[3]]).to_le_bytes()
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn s16(samples: &[i16]) -> Vec<u8> {
+        samples
+            .iter()
+            .flat_map(|sample| sample.to_ne_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn samples_are_written_little_endian() {
+        let mut writer = WavWriter::raw(Cursor::new(Vec::new()), 1, 2);
+        writer.write_samples(&s16(&[0x1234, -2])).unwrap();
+        assert_eq!(writer.writer.into_inner(), [0x34, 0x12, 0xfe, 0xff]);
+    }
+
+    #[test]
+    fn header_sizes_are_patched_in() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 2, 24_000).unwrap();
+        writer.write_channels(&[s16(&[1, 2]), s16(&[3])]).unwrap();
+        writer.finish().unwrap();
+        let wav = writer.writer.into_inner();
+        assert_eq!(wav.len(), HEADER_LEN as usize + 8);
+        assert_eq!(wav[4..8], 44u32.to_le_bytes());
+        assert_eq!(wav[40..44], 8u32.to_le_bytes());
+        assert_eq!(wav[44..], [1, 0, 3, 0, 2, 0, 0, 0]);
+    }
+}